@@ -1,3 +1,7 @@
+use std::fmt::{Display, Formatter};
+
+use crate::milter_error::MilterError;
+
 /// Defines the accept/reject actions that the milter returns for each step during the processing
 /// flow.
 pub enum AcceptRejectAction {
@@ -9,7 +13,251 @@ pub enum AcceptRejectAction {
     Discard,
     /// Reject the message without further processing
     Reject,
+    /// Reject (5xx) or temporarily fail (4xx) with a custom SMTP reply
+    ReplyCode(ReplyCode),
+    /// Skip the remaining body chunks and continue with the end of the body
+    ///
+    /// Only allowed in `MessageHandler::body_chunk` (or `body_chunk_raw`) and only if the MTA
+    /// supports skipping (`MilterProtocol::SKIP`, which is requested automatically). Otherwise it
+    /// is treated like `Continue`.
+    Skip,
     /// Temporarily fail without further processing
     Tempfail,
-    // TODO ReplyCode
+}
+
+/// Maximum number of lines of a multi-line reply (same as in libmilter).
+const MAX_REPLY_LINES: usize = 32;
+
+/// Maximum length of a single reply line including the reply code (see RFC 5321).
+const MAX_REPLY_LINE_LENGTH: usize = 512;
+
+/// A custom SMTP reply sent to the client (SMFIR_REPLYCODE).
+///
+/// The reply is validated when it is created, so an invalid reply never reaches the MTA.
+#[derive(Clone, Debug)]
+pub struct ReplyCode {
+    code: u16,
+    enhanced_code: Option<String>,
+    lines: Vec<String>,
+}
+
+impl ReplyCode {
+    /// Returns the SMTP reply code (4xx or 5xx).
+    pub fn code(&self) -> u16 {
+        self.code
+    }
+
+    /// Returns the reply as sent to the MTA (SMFIR_REPLYCODE).
+    ///
+    /// The MTA treats the reply text as a format string and ignores replies with a lone `%`, so
+    /// `%` is escaped as `%%`.
+    pub(crate) fn encode(&self) -> String {
+        self.to_string().replace('%', "%%")
+    }
+
+    /// Returns the enhanced status code (RFC 3463), if any.
+    pub fn enhanced_code(&self) -> Option<&str> {
+        self.enhanced_code.as_deref()
+    }
+
+    /// Returns the lines of the reply text.
+    pub fn lines(&self) -> &[String] {
+        &self.lines
+    }
+
+    /// Creates a single-line reply.
+    ///
+    /// - `code` is the SMTP reply code, which must be a 4xx or 5xx code.
+    /// - `enhanced_code` is an optional enhanced status code (RFC 3463) like `5.7.1`, whose class
+    ///   must match the first digit of `code`.
+    /// - `text` is the reply text.
+    ///
+    /// # Example:
+    /// ```
+    /// use rmilter::accept_reject_action::{AcceptRejectAction, ReplyCode};
+    /// use rmilter::message_handler::MessageHandler;
+    /// use rmilter::session_context::SessionContext;
+    ///
+    /// struct MyMessageHandler {}
+    ///
+    /// impl MessageHandler for MyMessageHandler {
+    ///     fn helo(&mut self, ctx: &SessionContext, msg: &str) -> AcceptRejectAction {
+    ///         match ReplyCode::new(550, Some("5.7.1"), "Go away") {
+    ///             Ok(reply_code) => AcceptRejectAction::ReplyCode(reply_code),
+    ///             Err(_) => AcceptRejectAction::Reject,
+    ///         }
+    ///     }
+    /// }
+    /// ```
+    pub fn new(code: u16, enhanced_code: Option<&str>, text: &str) -> Result<Self, MilterError> {
+        Self::new_multiline(code, enhanced_code, &[text])
+    }
+
+    /// Creates a multi-line reply.
+    ///
+    /// See `ReplyCode::new` for the requirements of `code` and `enhanced_code`. At most 32 lines
+    /// are allowed.
+    ///
+    /// # Example:
+    /// ```
+    /// use rmilter::accept_reject_action::ReplyCode;
+    ///
+    /// let reply_code = ReplyCode::new_multiline(
+    ///     451,
+    ///     Some("4.7.1"),
+    ///     &["Please try again later", "See https://example.com/greylisting"],
+    /// )
+    /// .expect("Invalid reply code");
+    ///
+    /// assert_eq!(
+    ///     "451-4.7.1 Please try again later\r\n451 4.7.1 See https://example.com/greylisting",
+    ///     reply_code.to_string()
+    /// );
+    /// ```
+    pub fn new_multiline(
+        code: u16,
+        enhanced_code: Option<&str>,
+        lines: &[&str],
+    ) -> Result<Self, MilterError> {
+        if !(400..600).contains(&code) {
+            return Err(MilterError::InvalidReplyCode(format!(
+                "reply code {} is not a 4xx or 5xx code",
+                code
+            )));
+        }
+
+        if let Some(enhanced_code) = enhanced_code {
+            validate_enhanced_code(code, enhanced_code)?;
+        }
+
+        if lines.is_empty() || lines.len() > MAX_REPLY_LINES {
+            return Err(MilterError::InvalidReplyCode(format!(
+                "reply must have between 1 and {} lines",
+                MAX_REPLY_LINES
+            )));
+        }
+
+        let prefix_len = 4 + enhanced_code.map(|e| e.len() + 1).unwrap_or(0);
+
+        for line in lines {
+            if line.contains(&['\r', '\n', '\0'][..]) {
+                return Err(MilterError::InvalidReplyCode(
+                    "reply text must not contain CR, LF or NUL".into(),
+                ));
+            }
+
+            if prefix_len + line.len() > MAX_REPLY_LINE_LENGTH {
+                return Err(MilterError::InvalidReplyCode(format!(
+                    "reply line exceeds {} characters",
+                    MAX_REPLY_LINE_LENGTH
+                )));
+            }
+        }
+
+        Ok(Self {
+            code,
+            enhanced_code: enhanced_code.map(String::from),
+            lines: lines.iter().map(|line| String::from(*line)).collect(),
+        })
+    }
+}
+
+impl Display for ReplyCode {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        for (i, line) in self.lines.iter().enumerate() {
+            let separator = if i + 1 < self.lines.len() { '-' } else { ' ' };
+
+            if i > 0 {
+                write!(f, "\r\n")?;
+            }
+
+            write!(f, "{}{}", self.code, separator)?;
+
+            if let Some(enhanced_code) = &self.enhanced_code {
+                write!(f, "{} ", enhanced_code)?;
+            }
+
+            write!(f, "{}", line)?;
+        }
+
+        Ok(())
+    }
+}
+
+fn validate_enhanced_code(code: u16, enhanced_code: &str) -> Result<(), MilterError> {
+    let parts: Vec<&str> = enhanced_code.split('.').collect();
+
+    let valid = match parts.as_slice() {
+        [class, subject, detail] => {
+            class.len() == 1
+                && [subject, detail]
+                    .iter()
+                    .all(|p| (1..=3).contains(&p.len()) && p.bytes().all(|b| b.is_ascii_digit()))
+        }
+        _ => false,
+    };
+
+    if !valid {
+        return Err(MilterError::InvalidReplyCode(format!(
+            "invalid enhanced status code '{}'",
+            enhanced_code
+        )));
+    }
+
+    if !enhanced_code.starts_with(char::from(b'0' + (code / 100) as u8)) {
+        return Err(MilterError::InvalidReplyCode(format!(
+            "enhanced status code '{}' does not match reply code {}",
+            enhanced_code, code
+        )));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reply_code_single_line() {
+        let res = ReplyCode::new(550, Some("5.7.1"), "Rejected").unwrap();
+
+        assert_eq!("550 5.7.1 Rejected", res.to_string());
+    }
+
+    #[test]
+    fn reply_code_without_enhanced_code() {
+        let res = ReplyCode::new_multiline(451, None, &["a", "b"]).unwrap();
+
+        assert_eq!("451-a\r\n451 b", res.to_string());
+    }
+
+    #[test]
+    fn reply_code_success_code_is_rejected() {
+        assert!(ReplyCode::new(250, None, "OK").is_err());
+    }
+
+    #[test]
+    fn reply_code_mismatching_enhanced_code_is_rejected() {
+        assert!(ReplyCode::new(550, Some("4.7.1"), "Rejected").is_err());
+    }
+
+    #[test]
+    fn reply_code_invalid_enhanced_code_is_rejected() {
+        assert!(ReplyCode::new(550, Some("5.7"), "Rejected").is_err());
+        assert!(ReplyCode::new(550, Some("5.a.1"), "Rejected").is_err());
+    }
+
+    #[test]
+    fn reply_code_line_break_is_rejected() {
+        assert!(ReplyCode::new(550, None, "Rejected\r\n250 OK").is_err());
+    }
+
+    #[test]
+    fn reply_code_percent_is_escaped() {
+        let res = ReplyCode::new(550, Some("5.7.1"), "100% spam").unwrap();
+
+        assert_eq!("550 5.7.1 100% spam", res.to_string());
+        assert_eq!("550 5.7.1 100%% spam", res.encode());
+    }
 }
@@ -0,0 +1,535 @@
+use std::future::Future;
+
+use crate::accept_reject_action::AcceptRejectAction;
+use crate::async_message_modifier::AsyncMessageModifier;
+use crate::message_handler::TimeoutKind;
+use crate::milter_message::{MilterHeader, MilterMacro, ProtocolFamily};
+use crate::session_context::SessionContext;
+
+/// Implement this trait to define the behavior of your milter application when using the tokio
+/// runtime (see `AsyncMilterBuilder`).
+///
+/// This is the async counterpart of `MessageHandler`: all methods return a future, so a handler
+/// can wait for other services (e.g. databases or HTTP APIs) without blocking a thread. The
+/// methods can be implemented using `async fn`.
+///
+/// All methods have a default implementation which returns AcceptRejectAction::Continue. Overwrite
+/// any of these methods to implement the desired behavior.
+///
+/// Each method receives the `SessionContext` of the connection, which contains the connection
+/// information, envelope, headers and macros received so far.
+pub trait AsyncMessageHandler: Send {
+    /// Milter checks for the current message have been aborted (SMFIC_ABORT).
+    ///
+    /// `ctx` still contains the aborted message, its message part is reset afterwards.
+    ///
+    /// # Example:
+    /// ```
+    /// use rmilter::async_message_handler::AsyncMessageHandler;
+    /// use rmilter::session_context::SessionContext;
+    ///
+    /// struct MyMessageHandler {}
+    ///
+    /// impl AsyncMessageHandler for MyMessageHandler {
+    ///     async fn abort_filter_checks(&mut self, ctx: &SessionContext) {
+    ///         println!("Aborted message {}", ctx.message_id());
+    ///     }
+    /// }
+    /// ```
+    #[allow(unused_variables)]
+    fn abort_filter_checks(&mut self, ctx: &SessionContext) -> impl Future<Output = ()> + Send {
+        async {}
+    }
+
+    /// A body chunk of the incoming email (SMFIC_BODY).
+    ///
+    /// - `value` contains the value of the body chunk (see `body_chunk_raw` for the exact bytes).
+    ///
+    /// Return `AcceptRejectAction::Skip` to skip the remaining body chunks (requires an MTA
+    /// supporting `MilterProtocol::SKIP`).
+    ///
+    /// # Example:
+    /// ```
+    /// use rmilter::accept_reject_action::AcceptRejectAction;
+    /// use rmilter::async_message_handler::AsyncMessageHandler;
+    /// use rmilter::session_context::SessionContext;
+    ///
+    /// struct MyMessageHandler {}
+    ///
+    /// impl AsyncMessageHandler for MyMessageHandler {
+    ///     async fn body_chunk(
+    ///         &mut self,
+    ///         ctx: &SessionContext,
+    ///         value: &str,
+    ///     ) -> AcceptRejectAction {
+    ///         println!("value: {}", value);
+    ///         AcceptRejectAction::Continue
+    ///     }
+    /// }
+    /// ```
+    #[allow(unused_variables)]
+    fn body_chunk(
+        &mut self,
+        ctx: &SessionContext,
+        value: &str,
+    ) -> impl Future<Output = AcceptRejectAction> + Send {
+        async { AcceptRejectAction::Continue }
+    }
+
+    /// A body chunk of the incoming email with the exact bytes sent by the MTA (SMFIC_BODY).
+    ///
+    /// - `value` contains the bytes of the body chunk.
+    ///
+    /// Overwrite this method instead of `body_chunk` to process binary content or 8-bit
+    /// charsets. By default, the bytes are converted to a string (invalid UTF-8 sequences are
+    /// replaced) and passed to `body_chunk`.
+    ///
+    /// # Example:
+    /// ```
+    /// use rmilter::accept_reject_action::AcceptRejectAction;
+    /// use rmilter::async_message_handler::AsyncMessageHandler;
+    /// use rmilter::session_context::SessionContext;
+    ///
+    /// struct MyMessageHandler {
+    ///     body_size: usize,
+    /// }
+    ///
+    /// impl AsyncMessageHandler for MyMessageHandler {
+    ///     async fn body_chunk_raw(
+    ///         &mut self,
+    ///         ctx: &SessionContext,
+    ///         value: &[u8],
+    ///     ) -> AcceptRejectAction {
+    ///         self.body_size += value.len();
+    ///         AcceptRejectAction::Continue
+    ///     }
+    /// }
+    /// ```
+    fn body_chunk_raw(
+        &mut self,
+        ctx: &SessionContext,
+        value: &[u8],
+    ) -> impl Future<Output = AcceptRejectAction> + Send {
+        async move { self.body_chunk(ctx, &String::from_utf8_lossy(value)).await }
+    }
+
+    /// The connection is closed or will be reused for a new SMTP session (SMFIC_QUIT_NC).
+    ///
+    /// This is called exactly once when the connection ends, no matter why (SMFIC_QUIT, the MTA
+    /// closing the connection, a failed option negotiation, a timeout or an error). Reset any
+    /// per-connection state here.
+    ///
+    /// # Example:
+    /// ```
+    /// use rmilter::async_message_handler::AsyncMessageHandler;
+    /// use rmilter::session_context::SessionContext;
+    ///
+    /// struct MyMessageHandler {
+    ///     helo: Option<String>,
+    /// }
+    ///
+    /// impl AsyncMessageHandler for MyMessageHandler {
+    ///     async fn close(&mut self, ctx: &SessionContext) {
+    ///         self.helo = None;
+    ///     }
+    /// }
+    /// ```
+    #[allow(unused_variables)]
+    fn close(&mut self, ctx: &SessionContext) -> impl Future<Output = ()> + Send {
+        async {}
+    }
+
+    /// Provides information about the connection to the MTA (SMFIC_CONNECT).
+    ///
+    /// - `hostname` The hostname of the machine running the MTA.
+    /// - `family` The protocol family used.
+    /// - `port` The used port (Inet4 and Inet6 only).
+    /// - `address` The IP address or socket path used.
+    ///
+    /// # Example:
+    /// ```
+    /// use rmilter::accept_reject_action::AcceptRejectAction;
+    /// use rmilter::async_message_handler::AsyncMessageHandler;
+    /// use rmilter::milter_message::ProtocolFamily;
+    /// use rmilter::session_context::SessionContext;
+    ///
+    /// struct MyMessageHandler {}
+    ///
+    /// impl AsyncMessageHandler for MyMessageHandler {
+    ///     async fn connection(
+    ///     &mut self,
+    ///     ctx: &SessionContext,
+    ///     hostname: &str,
+    ///     family: &ProtocolFamily,
+    ///     port: &u16,
+    ///     address: &str,
+    ///     ) -> AcceptRejectAction {
+    ///         println!(
+    ///             "hostname: {}, family: {:?}, port: {}, address: {}",
+    ///             hostname, family, port, address
+    ///         );
+    ///         AcceptRejectAction::Continue
+    ///     }
+    /// }
+    /// ```
+    #[allow(unused_variables)]
+    fn connection(
+        &mut self,
+        ctx: &SessionContext,
+        hostname: &str,
+        family: &ProtocolFamily,
+        port: &u16,
+        address: &str,
+    ) -> impl Future<Output = AcceptRejectAction> + Send {
+        async { AcceptRejectAction::Continue }
+    }
+
+    /// The client sent the DATA command (SMFIC_DATA).
+    ///
+    /// Can be disabled with `MilterProtocol::NO_DATA`.
+    ///
+    /// # Example:
+    /// ```
+    /// use rmilter::accept_reject_action::AcceptRejectAction;
+    /// use rmilter::async_message_handler::AsyncMessageHandler;
+    /// use rmilter::session_context::SessionContext;
+    ///
+    /// struct MyMessageHandler {}
+    ///
+    /// impl AsyncMessageHandler for MyMessageHandler {
+    ///     async fn data(&mut self, ctx: &SessionContext) -> AcceptRejectAction {
+    ///         println!("Data");
+    ///         AcceptRejectAction::Continue
+    ///     }
+    /// }
+    /// ```
+    #[allow(unused_variables)]
+    fn data(&mut self, ctx: &SessionContext) -> impl Future<Output = AcceptRejectAction> + Send {
+        async { AcceptRejectAction::Continue }
+    }
+
+    /// A set of macros defined by the MTA (SMFIC_MACRO).
+    ///
+    /// - `cmdcode` represents the command for which the macros are defined.
+    /// - `macros` contains the defined macros (see `SessionContext::macros` for all macros).
+    ///
+    /// # Example:
+    /// ```
+    /// use rmilter::async_message_handler::AsyncMessageHandler;
+    /// use rmilter::milter_message::MilterMacro;
+    /// use rmilter::session_context::SessionContext;
+    ///
+    /// struct MyMessageHandler {}
+    ///
+    /// impl AsyncMessageHandler for MyMessageHandler {
+    ///     async fn define_macros(
+    ///         &mut self,
+    ///         ctx: &SessionContext,
+    ///         cmdcode: &char,
+    ///         macros: Vec<MilterMacro>,
+    ///     ) {
+    ///         for m in macros {
+    ///             println!("cmdcode: {}, {}={}", cmdcode, m.name(), m.value());
+    ///         }
+    ///     }
+    /// }
+    /// ```
+    #[allow(unused_variables)]
+    fn define_macros(
+        &mut self,
+        ctx: &SessionContext,
+        cmdcode: &char,
+        macros: Vec<MilterMacro>,
+    ) -> impl Future<Output = ()> + Send {
+        async {}
+    }
+
+    /// The MTA informs that all body chunks of the message are sent (SMFIC_BODYEOB).
+    ///
+    /// - `modifier` can be used to modify the message before the returned action is sent.
+    ///
+    /// # Example:
+    /// ```
+    /// use rmilter::accept_reject_action::AcceptRejectAction;
+    /// use rmilter::async_message_handler::AsyncMessageHandler;
+    /// use rmilter::async_message_modifier::AsyncMessageModifier;
+    /// use rmilter::session_context::SessionContext;
+    ///
+    /// struct MyMessageHandler {}
+    ///
+    /// impl AsyncMessageHandler for MyMessageHandler {
+    ///     async fn end_of_body(
+    ///         &mut self,
+    ///         ctx: &SessionContext,
+    ///         modifier: &mut AsyncMessageModifier<'_>,
+    ///     ) -> AcceptRejectAction {
+    ///         println!("End of body");
+    ///         AcceptRejectAction::Continue
+    ///     }
+    /// }
+    /// ```
+    #[allow(unused_variables)]
+    fn end_of_body(
+        &mut self,
+        ctx: &SessionContext,
+        modifier: &mut AsyncMessageModifier<'_>,
+    ) -> impl Future<Output = AcceptRejectAction> + Send {
+        async { AcceptRejectAction::Continue }
+    }
+
+    /// The MTA informs that all header chunks of the message are sent (SMFIC_EOH).
+    ///
+    /// # Example:
+    /// ```
+    /// use rmilter::accept_reject_action::AcceptRejectAction;
+    /// use rmilter::async_message_handler::AsyncMessageHandler;
+    /// use rmilter::session_context::SessionContext;
+    ///
+    /// struct MyMessageHandler {}
+    ///
+    /// impl AsyncMessageHandler for MyMessageHandler {
+    ///     async fn end_of_header(&mut self, ctx: &SessionContext) -> AcceptRejectAction {
+    ///         println!("End of header");
+    ///         AcceptRejectAction::Continue
+    ///     }
+    /// }
+    /// ```
+    #[allow(unused_variables)]
+    fn end_of_header(
+        &mut self,
+        ctx: &SessionContext,
+    ) -> impl Future<Output = AcceptRejectAction> + Send {
+        async { AcceptRejectAction::Continue }
+    }
+
+    /// A header chunk (SMFIC_HEADER).
+    ///
+    /// - `name` defines the name of the provided value.
+    /// - `value` contains the actual value (see `header_raw` for the exact bytes).
+    ///
+    /// # Example:
+    /// ```
+    /// use rmilter::accept_reject_action::AcceptRejectAction;
+    /// use rmilter::async_message_handler::AsyncMessageHandler;
+    /// use rmilter::session_context::SessionContext;
+    ///
+    /// struct MyMessageHandler {}
+    ///
+    /// impl AsyncMessageHandler for MyMessageHandler {
+    ///     async fn header(
+    ///         &mut self,
+    ///         ctx: &SessionContext,
+    ///         name: &str,
+    ///         value: &str,
+    ///     ) -> AcceptRejectAction {
+    ///         println!("name: {}, value: {}", name, value);
+    ///         AcceptRejectAction::Continue
+    ///     }
+    /// }
+    /// ```
+    #[allow(unused_variables)]
+    fn header(
+        &mut self,
+        ctx: &SessionContext,
+        name: &str,
+        value: &str,
+    ) -> impl Future<Output = AcceptRejectAction> + Send {
+        async { AcceptRejectAction::Continue }
+    }
+
+    /// A header chunk including the exact bytes sent by the MTA (SMFIC_HEADER).
+    ///
+    /// - `header` contains the name, the raw value and the decoded value.
+    ///
+    /// Overwrite this method instead of `header` if the exact bytes of the value are needed
+    /// (e.g. for DKIM). By default, the name and the decoded value are passed to `header`.
+    ///
+    /// # Example:
+    /// ```
+    /// use rmilter::accept_reject_action::AcceptRejectAction;
+    /// use rmilter::async_message_handler::AsyncMessageHandler;
+    /// use rmilter::milter_message::MilterHeader;
+    /// use rmilter::session_context::SessionContext;
+    ///
+    /// struct MyMessageHandler {}
+    ///
+    /// impl AsyncMessageHandler for MyMessageHandler {
+    ///     async fn header_raw(
+    ///         &mut self,
+    ///         ctx: &SessionContext,
+    ///         header: &MilterHeader,
+    ///     ) -> AcceptRejectAction {
+    ///         println!("name: {}, raw value: {:?}", header.name(), header.raw_value());
+    ///         AcceptRejectAction::Continue
+    ///     }
+    /// }
+    /// ```
+    fn header_raw(
+        &mut self,
+        ctx: &SessionContext,
+        header: &MilterHeader,
+    ) -> impl Future<Output = AcceptRejectAction> + Send {
+        async move { self.header(ctx, header.name(), header.value()).await }
+    }
+
+    /// A helo message (SMFIC_HELO).
+    ///
+    /// - `msg` contains the sent helo message.
+    ///
+    /// # Example:
+    /// ```
+    /// use rmilter::accept_reject_action::AcceptRejectAction;
+    /// use rmilter::async_message_handler::AsyncMessageHandler;
+    /// use rmilter::session_context::SessionContext;
+    ///
+    /// struct MyMessageHandler {}
+    ///
+    /// impl AsyncMessageHandler for MyMessageHandler {
+    ///     async fn helo(&mut self, ctx: &SessionContext, msg: &str) -> AcceptRejectAction {
+    ///         println!("msg: {}", msg);
+    ///         AcceptRejectAction::Continue
+    ///     }
+    /// }
+    /// ```
+    #[allow(unused_variables)]
+    fn helo(
+        &mut self,
+        ctx: &SessionContext,
+        msg: &str,
+    ) -> impl Future<Output = AcceptRejectAction> + Send {
+        async { AcceptRejectAction::Continue }
+    }
+
+    /// A mail from message (SMFIC_MAIL).
+    ///
+    /// - `address` contains the address of the sender.
+    /// - `args` contains optional arguments.
+    ///
+    /// # Example:
+    /// ```
+    /// use rmilter::accept_reject_action::AcceptRejectAction;
+    /// use rmilter::async_message_handler::AsyncMessageHandler;
+    /// use rmilter::session_context::SessionContext;
+    ///
+    /// struct MyMessageHandler {}
+    ///
+    /// impl AsyncMessageHandler for MyMessageHandler {
+    ///     async fn mail_from(
+    ///         &mut self,
+    ///         ctx: &SessionContext,
+    ///         address: &str,
+    ///         args: &[String],
+    ///     ) -> AcceptRejectAction {
+    ///         println!("address: {}, args: {:?}", address, args);
+    ///         AcceptRejectAction::Continue
+    ///     }
+    /// }
+    /// ```
+    #[allow(unused_variables)]
+    fn mail_from(
+        &mut self,
+        ctx: &SessionContext,
+        address: &str,
+        args: &[String],
+    ) -> impl Future<Output = AcceptRejectAction> + Send {
+        async { AcceptRejectAction::Continue }
+    }
+
+    /// Recipient information (SMFIC_RCPT).
+    ///
+    /// - `recipient` contains the recipient of the message.
+    /// - `args` contains optional arguments.
+    ///
+    /// # Example:
+    /// ```
+    /// use rmilter::accept_reject_action::AcceptRejectAction;
+    /// use rmilter::async_message_handler::AsyncMessageHandler;
+    /// use rmilter::session_context::SessionContext;
+    ///
+    /// struct MyMessageHandler {}
+    ///
+    /// impl AsyncMessageHandler for MyMessageHandler {
+    ///     async fn recipient(
+    ///         &mut self,
+    ///         ctx: &SessionContext,
+    ///         recipient: &str,
+    ///         args: &[String],
+    ///     ) -> AcceptRejectAction {
+    ///         println!("recipient: {}, args: {:?}", recipient, args);
+    ///         AcceptRejectAction::Continue
+    ///     }
+    /// }
+    /// ```
+    #[allow(unused_variables)]
+    fn recipient(
+        &mut self,
+        ctx: &SessionContext,
+        recipient: &str,
+        args: &[String],
+    ) -> impl Future<Output = AcceptRejectAction> + Send {
+        async { AcceptRejectAction::Continue }
+    }
+
+    /// The connection is closed because a timeout has been exceeded.
+    ///
+    /// - `kind` defines which timeout has been exceeded.
+    ///
+    /// `close` is called afterwards.
+    ///
+    /// # Example:
+    /// ```
+    /// use rmilter::async_message_handler::AsyncMessageHandler;
+    /// use rmilter::message_handler::TimeoutKind;
+    /// use rmilter::session_context::SessionContext;
+    ///
+    /// struct MyMessageHandler {}
+    ///
+    /// impl AsyncMessageHandler for MyMessageHandler {
+    ///     async fn timeout(&mut self, ctx: &SessionContext, kind: TimeoutKind) {
+    ///         println!("Connection timed out: {:?}", kind);
+    ///     }
+    /// }
+    /// ```
+    #[allow(unused_variables)]
+    fn timeout(
+        &mut self,
+        ctx: &SessionContext,
+        kind: TimeoutKind,
+    ) -> impl Future<Output = ()> + Send {
+        async {}
+    }
+
+    /// An unknown or unimplemented SMTP command sent by the client (SMFIC_UNKNOWN).
+    ///
+    /// - `command` contains the complete command line including arguments.
+    ///
+    /// Can be disabled with `MilterProtocol::NO_UNKNOWN`.
+    ///
+    /// # Example:
+    /// ```
+    /// use rmilter::accept_reject_action::AcceptRejectAction;
+    /// use rmilter::async_message_handler::AsyncMessageHandler;
+    /// use rmilter::session_context::SessionContext;
+    ///
+    /// struct MyMessageHandler {}
+    ///
+    /// impl AsyncMessageHandler for MyMessageHandler {
+    ///     async fn unknown_command(
+    ///         &mut self,
+    ///         ctx: &SessionContext,
+    ///         command: &str,
+    ///     ) -> AcceptRejectAction {
+    ///         println!("command: {}", command);
+    ///         AcceptRejectAction::Reject
+    ///     }
+    /// }
+    /// ```
+    #[allow(unused_variables)]
+    fn unknown_command(
+        &mut self,
+        ctx: &SessionContext,
+        command: &str,
+    ) -> impl Future<Output = AcceptRejectAction> + Send {
+        async { AcceptRejectAction::Continue }
+    }
+}
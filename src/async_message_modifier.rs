@@ -0,0 +1,502 @@
+use std::time::Duration;
+
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+
+use crate::async_session::with_timeout;
+use crate::milter_error::MilterError;
+use crate::milter_message::{MilterActions, ResponseMessage, MAX_BODY_CHUNK_SIZE};
+
+/// Used to modify the current message at the end of the body (SMFIC_BODYEOB) when using an
+/// `AsyncMessageHandler`.
+///
+/// This is the async counterpart of `MessageModifier`: every modification is checked against the
+/// actions the MTA offered during option negotiation and is written to the MTA (within the write
+/// timeout) once the returned future is awaited, i.e. before the AcceptRejectAction returned by
+/// `AsyncMessageHandler::end_of_body`. It can also be used to send progress notifications to the
+/// MTA during long-running checks.
+pub struct AsyncMessageModifier<'a> {
+    actions: MilterActions,
+    stream: &'a mut (dyn AsyncWrite + Unpin + Send),
+    write_timeout: Option<Duration>,
+}
+
+impl<'a> AsyncMessageModifier<'a> {
+    /// Returns the actions offered by the MTA during option negotiation.
+    ///
+    /// # Example:
+    /// ```
+    /// use rmilter::accept_reject_action::AcceptRejectAction;
+    /// use rmilter::async_message_handler::AsyncMessageHandler;
+    /// use rmilter::async_message_modifier::AsyncMessageModifier;
+    /// use rmilter::milter_message::MilterActions;
+    /// use rmilter::session_context::SessionContext;
+    ///
+    /// struct MyMessageHandler {}
+    ///
+    /// impl AsyncMessageHandler for MyMessageHandler {
+    ///     async fn end_of_body(
+    ///         &mut self,
+    ///         ctx: &SessionContext,
+    ///         modifier: &mut AsyncMessageModifier<'_>,
+    ///     ) -> AcceptRejectAction {
+    ///         if modifier.actions().contains(MilterActions::ADD_RECIPIENTS) {
+    ///             println!("Adding recipients is possible");
+    ///         }
+    ///         AcceptRejectAction::Continue
+    ///     }
+    /// }
+    /// ```
+    pub fn actions(&self) -> MilterActions {
+        self.actions
+    }
+
+    /// Adds a header at the end of the existing headers (SMFIR_ADDHEADER).
+    ///
+    /// Requires `MilterActions::ADD_HEADERS`.
+    ///
+    /// # Example:
+    /// ```
+    /// use rmilter::accept_reject_action::AcceptRejectAction;
+    /// use rmilter::async_message_handler::AsyncMessageHandler;
+    /// use rmilter::async_message_modifier::AsyncMessageModifier;
+    /// use rmilter::session_context::SessionContext;
+    ///
+    /// struct MyMessageHandler {}
+    ///
+    /// impl AsyncMessageHandler for MyMessageHandler {
+    ///     async fn end_of_body(
+    ///         &mut self,
+    ///         ctx: &SessionContext,
+    ///         modifier: &mut AsyncMessageModifier<'_>,
+    ///     ) -> AcceptRejectAction {
+    ///         match modifier.add_header("X-Spam-Status", "No").await {
+    ///             Ok(_) => AcceptRejectAction::Continue,
+    ///             Err(_) => AcceptRejectAction::Tempfail,
+    ///         }
+    ///     }
+    /// }
+    /// ```
+    pub async fn add_header(&mut self, name: &str, value: &str) -> Result<(), MilterError> {
+        self.check_action(MilterActions::ADD_HEADERS)?;
+        self.send(ResponseMessage::add_header(name, value)?).await
+    }
+
+    /// Adds a recipient to the envelope (SMFIR_ADDRCPT).
+    ///
+    /// Requires `MilterActions::ADD_RECIPIENTS`.
+    ///
+    /// # Example:
+    /// ```
+    /// use rmilter::accept_reject_action::AcceptRejectAction;
+    /// use rmilter::async_message_handler::AsyncMessageHandler;
+    /// use rmilter::async_message_modifier::AsyncMessageModifier;
+    /// use rmilter::session_context::SessionContext;
+    ///
+    /// struct MyMessageHandler {}
+    ///
+    /// impl AsyncMessageHandler for MyMessageHandler {
+    ///     async fn end_of_body(
+    ///         &mut self,
+    ///         ctx: &SessionContext,
+    ///         modifier: &mut AsyncMessageModifier<'_>,
+    ///     ) -> AcceptRejectAction {
+    ///         match modifier.add_recipient("<archive@example.org>").await {
+    ///             Ok(_) => AcceptRejectAction::Continue,
+    ///             Err(_) => AcceptRejectAction::Tempfail,
+    ///         }
+    ///     }
+    /// }
+    /// ```
+    pub async fn add_recipient(&mut self, recipient: &str) -> Result<(), MilterError> {
+        self.check_action(MilterActions::ADD_RECIPIENTS)?;
+        self.send(ResponseMessage::add_recipient(recipient)?).await
+    }
+
+    /// Adds a recipient including ESMTP arguments to the envelope (SMFIR_ADDRCPT_PAR).
+    ///
+    /// - `args` contains the ESMTP arguments separated by spaces (e.g. `NOTIFY=NEVER`).
+    ///
+    /// Requires `MilterActions::ADD_RECIPIENTS_WITH_ARGS`.
+    ///
+    /// # Example:
+    /// ```
+    /// use rmilter::accept_reject_action::AcceptRejectAction;
+    /// use rmilter::async_message_handler::AsyncMessageHandler;
+    /// use rmilter::async_message_modifier::AsyncMessageModifier;
+    /// use rmilter::session_context::SessionContext;
+    ///
+    /// struct MyMessageHandler {}
+    ///
+    /// impl AsyncMessageHandler for MyMessageHandler {
+    ///     async fn end_of_body(
+    ///         &mut self,
+    ///         ctx: &SessionContext,
+    ///         modifier: &mut AsyncMessageModifier<'_>,
+    ///     ) -> AcceptRejectAction {
+    ///         let res = modifier
+    ///             .add_recipient_with_args("<archive@example.org>", "NOTIFY=NEVER")
+    ///             .await;
+    ///
+    ///         match res {
+    ///             Ok(_) => AcceptRejectAction::Continue,
+    ///             Err(_) => AcceptRejectAction::Tempfail,
+    ///         }
+    ///     }
+    /// }
+    /// ```
+    pub async fn add_recipient_with_args(
+        &mut self,
+        recipient: &str,
+        args: &str,
+    ) -> Result<(), MilterError> {
+        self.check_action(MilterActions::ADD_RECIPIENTS_WITH_ARGS)?;
+        self.send(ResponseMessage::add_recipient_with_args(recipient, args)?)
+            .await
+    }
+
+    /// Changes the envelope sender (SMFIR_CHGFROM).
+    ///
+    /// - `sender` contains the new address of the sender.
+    /// - `args` contains optional ESMTP arguments separated by spaces (e.g. `SIZE=1234`).
+    ///
+    /// Requires `MilterActions::CHANGE_FROM`.
+    ///
+    /// # Example:
+    /// ```
+    /// use rmilter::accept_reject_action::AcceptRejectAction;
+    /// use rmilter::async_message_handler::AsyncMessageHandler;
+    /// use rmilter::async_message_modifier::AsyncMessageModifier;
+    /// use rmilter::session_context::SessionContext;
+    ///
+    /// struct MyMessageHandler {}
+    ///
+    /// impl AsyncMessageHandler for MyMessageHandler {
+    ///     async fn end_of_body(
+    ///         &mut self,
+    ///         ctx: &SessionContext,
+    ///         modifier: &mut AsyncMessageModifier<'_>,
+    ///     ) -> AcceptRejectAction {
+    ///         match modifier.change_from("<bounces@example.org>", None).await {
+    ///             Ok(_) => AcceptRejectAction::Continue,
+    ///             Err(_) => AcceptRejectAction::Tempfail,
+    ///         }
+    ///     }
+    /// }
+    /// ```
+    pub async fn change_from(
+        &mut self,
+        sender: &str,
+        args: Option<&str>,
+    ) -> Result<(), MilterError> {
+        self.check_action(MilterActions::CHANGE_FROM)?;
+        self.send(ResponseMessage::change_from(sender, args)?).await
+    }
+
+    /// Changes the value of the `index`-th occurrence (starting at 1) of the header `name`
+    /// (SMFIR_CHGHEADER).
+    ///
+    /// Requires `MilterActions::CHANGE_HEADERS`.
+    ///
+    /// # Example:
+    /// ```
+    /// use rmilter::accept_reject_action::AcceptRejectAction;
+    /// use rmilter::async_message_handler::AsyncMessageHandler;
+    /// use rmilter::async_message_modifier::AsyncMessageModifier;
+    /// use rmilter::session_context::SessionContext;
+    ///
+    /// struct MyMessageHandler {}
+    ///
+    /// impl AsyncMessageHandler for MyMessageHandler {
+    ///     async fn end_of_body(
+    ///         &mut self,
+    ///         ctx: &SessionContext,
+    ///         modifier: &mut AsyncMessageModifier<'_>,
+    ///     ) -> AcceptRejectAction {
+    ///         match modifier.change_header("Subject", 1, "[SPAM] Hello").await {
+    ///             Ok(_) => AcceptRejectAction::Continue,
+    ///             Err(_) => AcceptRejectAction::Tempfail,
+    ///         }
+    ///     }
+    /// }
+    /// ```
+    pub async fn change_header(
+        &mut self,
+        name: &str,
+        index: u32,
+        value: &str,
+    ) -> Result<(), MilterError> {
+        self.check_action(MilterActions::CHANGE_HEADERS)?;
+        self.send(ResponseMessage::change_header(name, index, value)?)
+            .await
+    }
+
+    fn check_action(&self, action: MilterActions) -> Result<(), MilterError> {
+        if self.actions.contains(action) {
+            Ok(())
+        } else {
+            Err(MilterError::ActionNotNegotiated(action))
+        }
+    }
+
+    /// Deletes the `index`-th occurrence (starting at 1) of the header `name` (SMFIR_CHGHEADER
+    /// with an empty value).
+    ///
+    /// Requires `MilterActions::CHANGE_HEADERS`.
+    ///
+    /// # Example:
+    /// ```
+    /// use rmilter::accept_reject_action::AcceptRejectAction;
+    /// use rmilter::async_message_handler::AsyncMessageHandler;
+    /// use rmilter::async_message_modifier::AsyncMessageModifier;
+    /// use rmilter::session_context::SessionContext;
+    ///
+    /// struct MyMessageHandler {}
+    ///
+    /// impl AsyncMessageHandler for MyMessageHandler {
+    ///     async fn end_of_body(
+    ///         &mut self,
+    ///         ctx: &SessionContext,
+    ///         modifier: &mut AsyncMessageModifier<'_>,
+    ///     ) -> AcceptRejectAction {
+    ///         match modifier.delete_header("X-Internal", 1).await {
+    ///             Ok(_) => AcceptRejectAction::Continue,
+    ///             Err(_) => AcceptRejectAction::Tempfail,
+    ///         }
+    ///     }
+    /// }
+    /// ```
+    pub async fn delete_header(&mut self, name: &str, index: u32) -> Result<(), MilterError> {
+        self.change_header(name, index, "").await
+    }
+
+    /// Removes a recipient from the envelope (SMFIR_DELRCPT).
+    ///
+    /// Requires `MilterActions::REMOVE_RECIPIENTS`.
+    ///
+    /// # Example:
+    /// ```
+    /// use rmilter::accept_reject_action::AcceptRejectAction;
+    /// use rmilter::async_message_handler::AsyncMessageHandler;
+    /// use rmilter::async_message_modifier::AsyncMessageModifier;
+    /// use rmilter::session_context::SessionContext;
+    ///
+    /// struct MyMessageHandler {}
+    ///
+    /// impl AsyncMessageHandler for MyMessageHandler {
+    ///     async fn end_of_body(
+    ///         &mut self,
+    ///         ctx: &SessionContext,
+    ///         modifier: &mut AsyncMessageModifier<'_>,
+    ///     ) -> AcceptRejectAction {
+    ///         match modifier.delete_recipient("<bob@example.org>").await {
+    ///             Ok(_) => AcceptRejectAction::Continue,
+    ///             Err(_) => AcceptRejectAction::Tempfail,
+    ///         }
+    ///     }
+    /// }
+    /// ```
+    pub async fn delete_recipient(&mut self, recipient: &str) -> Result<(), MilterError> {
+        self.check_action(MilterActions::REMOVE_RECIPIENTS)?;
+        self.send(ResponseMessage::delete_recipient(recipient)?)
+            .await
+    }
+
+    /// Inserts a header at position `index` (starting at 0) of the existing headers
+    /// (SMFIR_INSHEADER).
+    ///
+    /// Requires `MilterActions::ADD_HEADERS`.
+    ///
+    /// # Example:
+    /// ```
+    /// use rmilter::accept_reject_action::AcceptRejectAction;
+    /// use rmilter::async_message_handler::AsyncMessageHandler;
+    /// use rmilter::async_message_modifier::AsyncMessageModifier;
+    /// use rmilter::session_context::SessionContext;
+    ///
+    /// struct MyMessageHandler {}
+    ///
+    /// impl AsyncMessageHandler for MyMessageHandler {
+    ///     async fn end_of_body(
+    ///         &mut self,
+    ///         ctx: &SessionContext,
+    ///         modifier: &mut AsyncMessageModifier<'_>,
+    ///     ) -> AcceptRejectAction {
+    ///         match modifier.insert_header("X-Checked-By", 0, "rmilter").await {
+    ///             Ok(_) => AcceptRejectAction::Continue,
+    ///             Err(_) => AcceptRejectAction::Tempfail,
+    ///         }
+    ///     }
+    /// }
+    /// ```
+    pub async fn insert_header(
+        &mut self,
+        name: &str,
+        index: u32,
+        value: &str,
+    ) -> Result<(), MilterError> {
+        self.check_action(MilterActions::ADD_HEADERS)?;
+        self.send(ResponseMessage::insert_header(name, index, value)?)
+            .await
+    }
+
+    pub(crate) fn new(
+        stream: &'a mut (dyn AsyncWrite + Unpin + Send),
+        actions: MilterActions,
+        write_timeout: Option<Duration>,
+    ) -> Self {
+        Self {
+            actions,
+            stream,
+            write_timeout,
+        }
+    }
+
+    /// Informs the MTA that the milter is still working on the message (SMFIR_PROGRESS).
+    ///
+    /// Call this method regularly during long-running checks to prevent the MTA from running into
+    /// its milter timeout. No action has to be negotiated for this.
+    ///
+    /// # Example:
+    /// ```
+    /// use rmilter::accept_reject_action::AcceptRejectAction;
+    /// use rmilter::async_message_handler::AsyncMessageHandler;
+    /// use rmilter::async_message_modifier::AsyncMessageModifier;
+    /// use rmilter::session_context::SessionContext;
+    ///
+    /// struct MyMessageHandler {}
+    ///
+    /// impl AsyncMessageHandler for MyMessageHandler {
+    ///     async fn end_of_body(
+    ///         &mut self,
+    ///         ctx: &SessionContext,
+    ///         modifier: &mut AsyncMessageModifier<'_>,
+    ///     ) -> AcceptRejectAction {
+    ///         for _ in 0..3 {
+    ///             // Run a long-running check
+    ///             if modifier.progress().await.is_err() {
+    ///                 return AcceptRejectAction::Tempfail;
+    ///             }
+    ///         }
+    ///         AcceptRejectAction::Continue
+    ///     }
+    /// }
+    /// ```
+    pub async fn progress(&mut self) -> Result<(), MilterError> {
+        self.send(ResponseMessage::progress()?).await
+    }
+
+    /// Quarantines the message, i.e. puts it into the hold queue of the MTA (SMFIR_QUARANTINE).
+    ///
+    /// - `reason` describes why the message was quarantined.
+    ///
+    /// Requires `MilterActions::QUARANTINE`.
+    ///
+    /// # Example:
+    /// ```
+    /// use rmilter::accept_reject_action::AcceptRejectAction;
+    /// use rmilter::async_message_handler::AsyncMessageHandler;
+    /// use rmilter::async_message_modifier::AsyncMessageModifier;
+    /// use rmilter::session_context::SessionContext;
+    ///
+    /// struct MyMessageHandler {}
+    ///
+    /// impl AsyncMessageHandler for MyMessageHandler {
+    ///     async fn end_of_body(
+    ///         &mut self,
+    ///         ctx: &SessionContext,
+    ///         modifier: &mut AsyncMessageModifier<'_>,
+    ///     ) -> AcceptRejectAction {
+    ///         match modifier.quarantine("Suspicious attachment").await {
+    ///             Ok(_) => AcceptRejectAction::Continue,
+    ///             Err(_) => AcceptRejectAction::Tempfail,
+    ///         }
+    ///     }
+    /// }
+    /// ```
+    pub async fn quarantine(&mut self, reason: &str) -> Result<(), MilterError> {
+        self.check_action(MilterActions::QUARANTINE)?;
+        self.send(ResponseMessage::quarantine(reason)?).await
+    }
+
+    /// Replaces the body of the message (SMFIR_REPLBODY).
+    ///
+    /// Large bodies are automatically split into multiple chunks. Calling this method more than
+    /// once appends `body` to the replacement body sent before.
+    ///
+    /// Requires `MilterActions::CHANGE_BODY`.
+    ///
+    /// # Example:
+    /// ```
+    /// use rmilter::accept_reject_action::AcceptRejectAction;
+    /// use rmilter::async_message_handler::AsyncMessageHandler;
+    /// use rmilter::async_message_modifier::AsyncMessageModifier;
+    /// use rmilter::session_context::SessionContext;
+    ///
+    /// struct MyMessageHandler {}
+    ///
+    /// impl AsyncMessageHandler for MyMessageHandler {
+    ///     async fn end_of_body(
+    ///         &mut self,
+    ///         ctx: &SessionContext,
+    ///         modifier: &mut AsyncMessageModifier<'_>,
+    ///     ) -> AcceptRejectAction {
+    ///         match modifier.replace_body(b"The body has been removed.\r\n").await {
+    ///             Ok(_) => AcceptRejectAction::Continue,
+    ///             Err(_) => AcceptRejectAction::Tempfail,
+    ///         }
+    ///     }
+    /// }
+    /// ```
+    pub async fn replace_body(&mut self, body: &[u8]) -> Result<(), MilterError> {
+        self.check_action(MilterActions::CHANGE_BODY)?;
+
+        if body.is_empty() {
+            return self.send(ResponseMessage::replace_body(body)?).await;
+        }
+
+        for chunk in body.chunks(MAX_BODY_CHUNK_SIZE) {
+            self.send(ResponseMessage::replace_body(chunk)?).await?;
+        }
+
+        Ok(())
+    }
+
+    async fn send(&mut self, response_msg: ResponseMessage) -> Result<(), MilterError> {
+        let stream = &mut *self.stream;
+        let write = async {
+            stream.write_all(response_msg.get_content()).await?;
+            stream.flush().await
+        };
+
+        Ok(with_timeout(self.write_timeout, write).await?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn add_header_not_negotiated() {
+        let mut buf = Vec::new();
+        let mut modifier = AsyncMessageModifier::new(&mut buf, MilterActions::CHANGE_HEADERS, None);
+
+        assert!(matches!(
+            modifier.add_header("X-Spam-Status", "No").await,
+            Err(MilterError::ActionNotNegotiated(MilterActions::ADD_HEADERS))
+        ));
+        assert!(buf.is_empty());
+    }
+
+    #[tokio::test]
+    async fn replace_body_is_chunked() {
+        let body = vec![b'x'; MAX_BODY_CHUNK_SIZE + 10];
+        let mut buf = Vec::new();
+        let mut modifier = AsyncMessageModifier::new(&mut buf, MilterActions::CHANGE_BODY, None);
+
+        modifier.replace_body(&body).await.unwrap();
+
+        assert_eq!(body.len() + 2 * 5, buf.len());
+        assert_eq!(&[0, 1, 0, 0, b'b'], &buf[..5]);
+    }
+}
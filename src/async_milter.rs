@@ -0,0 +1,380 @@
+use std::future::Future;
+#[cfg(unix)]
+use std::path::Path;
+use std::pin::pin;
+use std::sync::Arc;
+
+use tokio::io::{AsyncRead, AsyncWrite};
+#[cfg(unix)]
+use tokio::net::UnixListener;
+use tokio::net::{TcpListener, ToSocketAddrs};
+use tokio::sync::{mpsc, OwnedSemaphorePermit, Semaphore};
+
+use crate::async_message_handler::AsyncMessageHandler;
+use crate::async_session::AsyncSession;
+use crate::milter::MilterConfig;
+#[cfg(unix)]
+use crate::milter::{remove_stale_socket, SocketFileGuard};
+use crate::milter_error::MilterError;
+use crate::milter_socket::MilterSocket;
+use crate::session::ActiveSessions;
+use crate::shutdown_handle::{ShutdownHandle, DEFAULT_SHUTDOWN_TIMEOUT};
+
+/// The async counterpart of `Milter`, running on the tokio runtime.
+///
+/// Each connection of the MTA is handled by its own tokio task using its own
+/// `AsyncMessageHandler`, created by the factory passed to `AsyncMilterBuilder::new`.
+pub struct AsyncMilter<F> {
+    config: Arc<MilterConfig>,
+    factory: Arc<F>,
+    sessions: Option<Arc<Semaphore>>,
+    shutdown: ShutdownHandle,
+}
+
+impl<F, H> AsyncMilter<F>
+where
+    F: Fn() -> H + Send + Sync + 'static,
+    H: AsyncMessageHandler + 'static,
+{
+    /// Handles the connections returned by `accept` until a shutdown is requested, then waits
+    /// for the running sessions to finish.
+    async fn accept_connections<S, A, R>(&self, mut accept: A) -> Result<(), MilterError>
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+        A: FnMut() -> R,
+        R: Future<Output = std::io::Result<S>>,
+    {
+        // Each session holds a sender, so the receiver ends once all sessions have finished
+        let (active_tx, mut active_rx) = mpsc::channel::<()>(1);
+        let mut shutdown = pin!(self.shutdown.wait());
+
+        let result = loop {
+            let accepted = tokio::select! {
+                _ = &mut shutdown => break Ok(()),
+                accepted = self.next_connection(accept()) => accepted,
+            };
+
+            match accepted {
+                Ok((stream, permit)) => self.handle_stream(stream, permit, active_tx.clone()),
+                Err(e) => break Err(e),
+            }
+        };
+
+        drop(active_tx);
+
+        if result.is_ok() {
+            active_rx.recv().await;
+        }
+
+        self.shutdown.stopped();
+
+        result
+    }
+
+    /// Handles a connection of the MTA on a new tokio task.
+    fn handle_stream<S>(
+        &self,
+        stream: S,
+        permit: Option<OwnedSemaphorePermit>,
+        active: mpsc::Sender<()>,
+    ) where
+        S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        let factory = Arc::clone(&self.factory);
+        let config = Arc::clone(&self.config);
+        let shutdown = self.shutdown.clone();
+
+        tokio::spawn(async move {
+            let _active = active;
+            let _permit = permit;
+            let mut message_handler = factory();
+
+            let closed = async {
+                shutdown.wait().await;
+                tokio::time::sleep(shutdown.timeout()).await;
+                eprintln!("Closing connection still active after the shutdown timeout");
+            };
+
+            if let Err(e) = AsyncSession::new(&mut message_handler, &config)
+                .handle_stream(stream, closed)
+                .await
+            {
+                eprintln!("Error while handling connection: {}", e);
+            }
+        });
+    }
+
+    pub(crate) fn new(factory: F, config: MilterConfig) -> Self {
+        Self {
+            sessions: config.max_sessions.map(|max| Arc::new(Semaphore::new(max))),
+            shutdown: ShutdownHandle::new(
+                config.shutdown_timeout.unwrap_or(DEFAULT_SHUTDOWN_TIMEOUT),
+                ActiveSessions::default(),
+            ),
+            config: Arc::new(config),
+            factory: Arc::new(factory),
+        }
+    }
+
+    /// Waits for a free session slot, then accepts the next connection.
+    async fn next_connection<S, A>(
+        &self,
+        accept: A,
+    ) -> Result<(S, Option<OwnedSemaphorePermit>), MilterError>
+    where
+        A: Future<Output = std::io::Result<S>>,
+    {
+        let permit = match &self.sessions {
+            Some(sessions) => Some(
+                Arc::clone(sessions)
+                    .acquire_owned()
+                    .await
+                    .expect("Semaphore is never closed"),
+            ),
+            None => None,
+        };
+
+        Ok((accept.await?, permit))
+    }
+
+    /// Opens the connection to the MTA service.
+    ///
+    /// - `address` defines the socket address of the MTA.
+    ///
+    /// # Example
+    /// ```no_run
+    /// use rmilter::async_message_handler::AsyncMessageHandler;
+    /// use rmilter::async_milter_builder::AsyncMilterBuilder;
+    ///
+    /// struct MyHandler;
+    /// impl AsyncMessageHandler for MyHandler {}
+    ///
+    /// #[tokio::main]
+    /// async fn main() {
+    ///     let milter = AsyncMilterBuilder::new(|| MyHandler {}).build();
+    ///
+    ///     milter
+    ///         .run("127.0.0.1:31337")
+    ///         .await
+    ///         .expect("Failed to start milter");
+    /// }
+    /// ```
+    pub async fn run<A: ToSocketAddrs>(&self, address: A) -> Result<(), MilterError> {
+        let listener = TcpListener::bind(address).await?;
+        let listener = &listener;
+
+        self.accept_connections(
+            move || async move { listener.accept().await.map(|(stream, _)| stream) },
+        )
+        .await
+    }
+
+    /// Returns a handle for stopping the milter, which can be used from another task.
+    ///
+    /// See `ShutdownHandle` for details.
+    ///
+    /// # Example
+    /// ```no_run
+    /// use rmilter::async_message_handler::AsyncMessageHandler;
+    /// use rmilter::async_milter_builder::AsyncMilterBuilder;
+    ///
+    /// struct MyHandler;
+    /// impl AsyncMessageHandler for MyHandler {}
+    ///
+    /// #[tokio::main]
+    /// async fn main() {
+    ///     let milter = AsyncMilterBuilder::new(|| MyHandler {}).build();
+    ///     let shutdown_handle = milter.shutdown_handle();
+    ///
+    ///     tokio::spawn(async move {
+    ///         // Wait for SIGTERM, e.g. using tokio::signal
+    ///         shutdown_handle.shutdown();
+    ///     });
+    ///
+    ///     milter
+    ///         .run("127.0.0.1:31337")
+    ///         .await
+    ///         .expect("Failed to start milter");
+    /// }
+    /// ```
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        self.shutdown.clone()
+    }
+
+    /// Opens the connection to the MTA service using the socket defined with
+    /// `AsyncMilterBuilder::set_socket`.
+    ///
+    /// # Example
+    /// ```no_run
+    /// use rmilter::async_message_handler::AsyncMessageHandler;
+    /// use rmilter::async_milter_builder::AsyncMilterBuilder;
+    ///
+    /// struct MyHandler;
+    /// impl AsyncMessageHandler for MyHandler {}
+    ///
+    /// #[tokio::main]
+    /// async fn main() {
+    ///     let milter = AsyncMilterBuilder::new(|| MyHandler {})
+    ///         .set_socket("inet:8890@localhost".parse().expect("Invalid socket"))
+    ///         .build();
+    ///
+    ///     milter.start().await.expect("Failed to start milter");
+    /// }
+    /// ```
+    pub async fn start(&self) -> Result<(), MilterError> {
+        match self.config.socket.clone() {
+            #[cfg(unix)]
+            Some(MilterSocket::Unix(path)) => self.run_unix(path).await,
+            #[cfg(not(unix))]
+            Some(MilterSocket::Unix(path)) => Err(MilterError::InvalidSocket(format!(
+                "unix domain sockets are not supported on this platform: {}",
+                path.display()
+            ))),
+            Some(socket) => {
+                let addrs = socket.socket_addrs()?;
+                self.run(&addrs[..]).await
+            }
+            None => Err(MilterError::InvalidSocket("no socket defined".into())),
+        }
+    }
+
+    /// Opens the connection to the MTA service using a unix domain socket.
+    ///
+    /// - `path` defines the path of the socket.
+    ///
+    /// The socket file is handled the same way as in `Milter::run_unix`.
+    ///
+    /// # Example
+    /// ```no_run
+    /// use rmilter::async_message_handler::AsyncMessageHandler;
+    /// use rmilter::async_milter_builder::AsyncMilterBuilder;
+    ///
+    /// struct MyHandler;
+    /// impl AsyncMessageHandler for MyHandler {}
+    ///
+    /// #[tokio::main]
+    /// async fn main() {
+    ///     let milter = AsyncMilterBuilder::new(|| MyHandler {})
+    ///         .set_socket_permissions(0o660)
+    ///         .build();
+    ///
+    ///     milter
+    ///         .run_unix("/var/spool/postfix/milter/rmilter.sock")
+    ///         .await
+    ///         .expect("Failed to start milter");
+    /// }
+    /// ```
+    #[cfg(unix)]
+    pub async fn run_unix<P: AsRef<Path>>(&self, path: P) -> Result<(), MilterError> {
+        let path = path.as_ref();
+
+        // Checking for a stale socket connects to it, which blocks
+        let stale_path = path.to_path_buf();
+        tokio::task::spawn_blocking(move || remove_stale_socket(&stale_path))
+            .await
+            .map_err(std::io::Error::from)??;
+
+        let listener = UnixListener::bind(path)?;
+        let listener = &listener;
+        // Also removes the socket file if this future is dropped
+        let _socket_file = SocketFileGuard(path);
+
+        self.config.prepare_socket_file(path)?;
+
+        self.accept_connections(
+            move || async move { listener.accept().await.map(|(stream, _)| stream) },
+        )
+        .await
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use crate::async_milter_builder::AsyncMilterBuilder;
+    use crate::session_context::SessionContext;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+    use tokio::io::AsyncReadExt;
+    use tokio::net::UnixStream;
+
+    struct MyMessageHandler {
+        closed: Arc<AtomicUsize>,
+    }
+
+    impl AsyncMessageHandler for MyMessageHandler {
+        async fn close(&mut self, _ctx: &SessionContext) {
+            self.closed.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    #[tokio::test]
+    async fn shutdown_closes_sessions_after_timeout() {
+        let path = std::env::temp_dir().join(format!(
+            "rmilter-async-shutdown-{}.sock",
+            std::process::id()
+        ));
+        let started = Arc::new(AtomicUsize::new(0));
+        let closed = Arc::new(AtomicUsize::new(0));
+
+        let handler_started = Arc::clone(&started);
+        let handler_closed = Arc::clone(&closed);
+        let milter = AsyncMilterBuilder::new(move || {
+            handler_started.fetch_add(1, Ordering::SeqCst);
+            MyMessageHandler {
+                closed: Arc::clone(&handler_closed),
+            }
+        })
+        .set_shutdown_timeout(Duration::from_millis(50))
+        .build();
+        let shutdown_handle = milter.shutdown_handle();
+
+        let mta_path = path.clone();
+        let mta = tokio::spawn(async move {
+            let mta = loop {
+                match UnixStream::connect(&mta_path).await {
+                    Ok(mta) => break mta,
+                    Err(_) => tokio::time::sleep(Duration::from_millis(10)).await,
+                }
+            };
+
+            while started.load(Ordering::SeqCst) == 0 {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+
+            shutdown_handle.shutdown();
+            mta
+        });
+
+        milter.run_unix(&path).await.unwrap();
+
+        assert_eq!(1, closed.load(Ordering::SeqCst));
+        assert_eq!(0, mta.await.unwrap().read(&mut [0; 8]).await.unwrap());
+        assert!(!path.exists());
+    }
+
+    #[tokio::test]
+    async fn run_unix_removes_socket_file_when_dropped() {
+        let path =
+            std::env::temp_dir().join(format!("rmilter-async-dropped-{}.sock", std::process::id()));
+        let milter = AsyncMilterBuilder::new(|| MyMessageHandler {
+            closed: Arc::new(AtomicUsize::new(0)),
+        })
+        .build();
+
+        let run = milter.run_unix(&path);
+        let watched_path = path.clone();
+        let started = async {
+            while !watched_path.exists() {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        };
+
+        tokio::select! {
+            result = run => panic!("Milter stopped: {:?}", result.err()),
+            _ = started => {}
+        }
+
+        assert!(!path.exists());
+    }
+}
@@ -0,0 +1,324 @@
+use std::time::Duration;
+
+use crate::async_message_handler::AsyncMessageHandler;
+use crate::async_milter::AsyncMilter;
+use crate::milter::MilterConfig;
+use crate::milter_message::{MacroStage, MilterActions, MilterProtocol};
+use crate::milter_socket::MilterSocket;
+
+/// Used to build an AsyncMilter.
+///
+/// This is the async counterpart of `MilterBuilder` and supports the same configuration. The
+/// factory passed to `AsyncMilterBuilder::new` creates an `AsyncMessageHandler` for each
+/// connection of the MTA.
+///
+/// # Example
+/// ```
+/// use rmilter::async_message_handler::AsyncMessageHandler;
+/// use rmilter::async_milter_builder::AsyncMilterBuilder;
+///
+/// struct MyHandler;
+/// impl AsyncMessageHandler for MyHandler {}
+///
+/// let milter = AsyncMilterBuilder::new(|| MyHandler {})
+///     .build();
+/// ```
+pub struct AsyncMilterBuilder<F> {
+    config: MilterConfig,
+    factory: F,
+}
+
+impl<F, H> AsyncMilterBuilder<F>
+where
+    F: Fn() -> H + Send + Sync + 'static,
+    H: AsyncMessageHandler + 'static,
+{
+    /// Creates an AsyncMilter from the AsyncMilterBuilder configuration.
+    ///
+    /// # Example
+    /// ```
+    /// use rmilter::async_message_handler::AsyncMessageHandler;
+    /// use rmilter::async_milter_builder::AsyncMilterBuilder;
+    ///
+    /// struct MyHandler;
+    /// impl AsyncMessageHandler for MyHandler {}
+    ///
+    /// let milter = AsyncMilterBuilder::new(|| MyHandler {})
+    ///     .build();
+    /// ```
+    pub fn build(self) -> AsyncMilter<F> {
+        AsyncMilter::new(self.factory, self.config)
+    }
+
+    /// Creates a new AsyncMilterBuilder with a factory that creates an AsyncMessageHandler per
+    /// connection.
+    ///
+    /// # Example
+    /// ```
+    /// use rmilter::async_message_handler::AsyncMessageHandler;
+    /// use rmilter::async_milter_builder::AsyncMilterBuilder;
+    ///
+    /// struct MyHandler {
+    ///     headers: usize,
+    /// }
+    /// impl AsyncMessageHandler for MyHandler {}
+    ///
+    /// let milter = AsyncMilterBuilder::new(|| MyHandler { headers: 0 })
+    ///     .build();
+    /// ```
+    pub fn new(factory: F) -> Self {
+        Self {
+            config: MilterConfig::default(),
+            factory,
+        }
+    }
+
+    /// Requests the MTA to send the given macros at the given stage (SMFIR_SETSYMLIST).
+    ///
+    /// See `MilterBuilder::request_macros`.
+    ///
+    /// # Example
+    /// ```
+    /// use rmilter::async_message_handler::AsyncMessageHandler;
+    /// use rmilter::async_milter_builder::AsyncMilterBuilder;
+    /// use rmilter::milter_message::MacroStage;
+    ///
+    /// struct MyHandler;
+    /// impl AsyncMessageHandler for MyHandler {}
+    ///
+    /// let milter = AsyncMilterBuilder::new(|| MyHandler {})
+    ///     .request_macros(MacroStage::Connect, &["{client_ptr}", "{daemon_name}"])
+    ///     .build();
+    /// ```
+    pub fn request_macros(mut self, stage: MacroStage, macros: &[&str]) -> Self {
+        self.config.add_macros(stage, macros);
+        self
+    }
+
+    /// Used to define the actions the milter wants to perform on messages.
+    ///
+    /// See `MilterBuilder::set_actions`.
+    ///
+    /// # Example
+    /// ```
+    /// use rmilter::async_message_handler::AsyncMessageHandler;
+    /// use rmilter::async_milter_builder::AsyncMilterBuilder;
+    /// use rmilter::milter_message::MilterActions;
+    ///
+    /// struct MyHandler;
+    /// impl AsyncMessageHandler for MyHandler {}
+    ///
+    /// let milter = AsyncMilterBuilder::new(|| MyHandler {})
+    ///     .set_actions(MilterActions::ADD_HEADERS | MilterActions::QUARANTINE)
+    ///     .build();
+    /// ```
+    pub fn set_actions(mut self, actions: MilterActions) -> Self {
+        self.config.actions = Some(actions);
+        self
+    }
+
+    /// Used to define how long the MTA may stay silent between two messages of a connection.
+    ///
+    /// If no new message is received in time, `AsyncMessageHandler::timeout` is called with
+    /// `TimeoutKind::Idle` and the connection is closed. By default, there is no idle timeout.
+    /// A timeout of zero disables the timeout.
+    ///
+    /// # Example
+    /// ```
+    /// use std::time::Duration;
+    ///
+    /// use rmilter::async_message_handler::AsyncMessageHandler;
+    /// use rmilter::async_milter_builder::AsyncMilterBuilder;
+    ///
+    /// struct MyHandler;
+    /// impl AsyncMessageHandler for MyHandler {}
+    ///
+    /// let milter = AsyncMilterBuilder::new(|| MyHandler {})
+    ///     .set_idle_timeout(Duration::from_secs(600))
+    ///     .build();
+    /// ```
+    pub fn set_idle_timeout(mut self, timeout: Duration) -> Self {
+        self.config.idle_timeout = Some(timeout).filter(|t| !t.is_zero());
+        self
+    }
+
+    /// Used to define the maximum number of connections handled at the same time.
+    ///
+    /// Further connections are accepted once a running connection is closed. By default, the
+    /// number of connections is not limited.
+    ///
+    /// # Example
+    /// ```
+    /// use rmilter::async_message_handler::AsyncMessageHandler;
+    /// use rmilter::async_milter_builder::AsyncMilterBuilder;
+    ///
+    /// struct MyHandler;
+    /// impl AsyncMessageHandler for MyHandler {}
+    ///
+    /// let milter = AsyncMilterBuilder::new(|| MyHandler {})
+    ///     .set_max_connections(256)
+    ///     .build();
+    /// ```
+    pub fn set_max_connections(mut self, max_connections: usize) -> Self {
+        self.config.max_sessions = Some(max_connections.max(1));
+        self
+    }
+
+    /// Used to define the protocol for communicating with the MTA.
+    ///
+    /// See `MilterBuilder::set_protocol`.
+    ///
+    /// # Example
+    /// ```
+    /// use rmilter::async_message_handler::AsyncMessageHandler;
+    /// use rmilter::async_milter_builder::AsyncMilterBuilder;
+    /// use rmilter::milter_message::MilterProtocol;
+    ///
+    /// struct MyHandler;
+    /// impl AsyncMessageHandler for MyHandler {}
+    ///
+    /// let milter = AsyncMilterBuilder::new(|| MyHandler {})
+    ///     .set_protocol(MilterProtocol::NO_HELO | MilterProtocol::NO_REPLY_HEADER)
+    ///     .build();
+    /// ```
+    pub fn set_protocol(mut self, protocol: MilterProtocol) -> Self {
+        self.config.protocol = Some(protocol);
+        self
+    }
+
+    /// Used to define how long the MTA may take to send the rest of a started message.
+    ///
+    /// If the message is not complete in time, `AsyncMessageHandler::timeout` is called with
+    /// `TimeoutKind::Read` and the connection is closed. By default, there is no read timeout.
+    /// A timeout of zero disables the timeout.
+    ///
+    /// # Example
+    /// ```
+    /// use std::time::Duration;
+    ///
+    /// use rmilter::async_message_handler::AsyncMessageHandler;
+    /// use rmilter::async_milter_builder::AsyncMilterBuilder;
+    ///
+    /// struct MyHandler;
+    /// impl AsyncMessageHandler for MyHandler {}
+    ///
+    /// let milter = AsyncMilterBuilder::new(|| MyHandler {})
+    ///     .set_read_timeout(Duration::from_secs(10))
+    ///     .build();
+    /// ```
+    pub fn set_read_timeout(mut self, timeout: Duration) -> Self {
+        self.config.read_timeout = Some(timeout).filter(|t| !t.is_zero());
+        self
+    }
+
+    /// Used to define how long running sessions may take to finish after a shutdown has been
+    /// requested using the `ShutdownHandle` (30 seconds by default).
+    ///
+    /// Connections still open after this time are closed.
+    ///
+    /// # Example
+    /// ```
+    /// use std::time::Duration;
+    ///
+    /// use rmilter::async_message_handler::AsyncMessageHandler;
+    /// use rmilter::async_milter_builder::AsyncMilterBuilder;
+    ///
+    /// struct MyHandler;
+    /// impl AsyncMessageHandler for MyHandler {}
+    ///
+    /// let milter = AsyncMilterBuilder::new(|| MyHandler {})
+    ///     .set_shutdown_timeout(Duration::from_secs(60))
+    ///     .build();
+    /// ```
+    pub fn set_shutdown_timeout(mut self, timeout: Duration) -> Self {
+        self.config.shutdown_timeout = Some(timeout);
+        self
+    }
+
+    /// Used to define the socket the milter listens on when using `AsyncMilter::start`.
+    ///
+    /// See `MilterSocket` for the supported socket formats.
+    ///
+    /// # Example
+    /// ```
+    /// use rmilter::async_message_handler::AsyncMessageHandler;
+    /// use rmilter::async_milter_builder::AsyncMilterBuilder;
+    ///
+    /// struct MyHandler;
+    /// impl AsyncMessageHandler for MyHandler {}
+    ///
+    /// let milter = AsyncMilterBuilder::new(|| MyHandler {})
+    ///     .set_socket("unix:/var/run/milter.sock".parse().expect("Invalid socket"))
+    ///     .build();
+    /// ```
+    pub fn set_socket(mut self, socket: MilterSocket) -> Self {
+        self.config.socket = Some(socket);
+        self
+    }
+
+    /// Used to define the owner of the socket file created by `AsyncMilter::run_unix`.
+    ///
+    /// See `MilterBuilder::set_socket_owner`.
+    ///
+    /// # Example
+    /// ```
+    /// use rmilter::async_message_handler::AsyncMessageHandler;
+    /// use rmilter::async_milter_builder::AsyncMilterBuilder;
+    ///
+    /// struct MyHandler;
+    /// impl AsyncMessageHandler for MyHandler {}
+    ///
+    /// let milter = AsyncMilterBuilder::new(|| MyHandler {})
+    ///     .set_socket_owner(None, Some(89))
+    ///     .build();
+    /// ```
+    pub fn set_socket_owner(mut self, uid: Option<u32>, gid: Option<u32>) -> Self {
+        self.config.socket_owner = (uid, gid);
+        self
+    }
+
+    /// Used to define the permissions (e.g. `0o660`) of the socket file created by
+    /// `AsyncMilter::run_unix`.
+    ///
+    /// # Example
+    /// ```
+    /// use rmilter::async_message_handler::AsyncMessageHandler;
+    /// use rmilter::async_milter_builder::AsyncMilterBuilder;
+    ///
+    /// struct MyHandler;
+    /// impl AsyncMessageHandler for MyHandler {}
+    ///
+    /// let milter = AsyncMilterBuilder::new(|| MyHandler {})
+    ///     .set_socket_permissions(0o660)
+    ///     .build();
+    /// ```
+    pub fn set_socket_permissions(mut self, mode: u32) -> Self {
+        self.config.socket_permissions = Some(mode);
+        self
+    }
+
+    /// Used to define how long sending a reply to the MTA may take.
+    ///
+    /// If a reply can't be sent in time, `AsyncMessageHandler::timeout` is called with
+    /// `TimeoutKind::Write` and the connection is closed. By default, there is no write timeout.
+    /// A timeout of zero disables the timeout.
+    ///
+    /// # Example
+    /// ```
+    /// use std::time::Duration;
+    ///
+    /// use rmilter::async_message_handler::AsyncMessageHandler;
+    /// use rmilter::async_milter_builder::AsyncMilterBuilder;
+    ///
+    /// struct MyHandler;
+    /// impl AsyncMessageHandler for MyHandler {}
+    ///
+    /// let milter = AsyncMilterBuilder::new(|| MyHandler {})
+    ///     .set_write_timeout(Duration::from_secs(10))
+    ///     .build();
+    /// ```
+    pub fn set_write_timeout(mut self, timeout: Duration) -> Self {
+        self.config.write_timeout = Some(timeout).filter(|t| !t.is_zero());
+        self
+    }
+}
@@ -0,0 +1,421 @@
+use std::convert::TryFrom;
+use std::future::Future;
+use std::time::Duration;
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::accept_reject_action::AcceptRejectAction;
+use crate::async_message_handler::AsyncMessageHandler;
+use crate::async_message_modifier::AsyncMessageModifier;
+use crate::message_handler::TimeoutKind;
+use crate::milter::MilterConfig;
+use crate::milter_error::MilterError;
+use crate::milter_message::{MilterMessage, MilterProtocol, ResponseMessage};
+use crate::session::{is_timeout, next_message, reply_action};
+use crate::session_context::{ConnectionInfo, EnvelopeAddress, SessionContext};
+
+/// Handles a single connection of the MTA using an `AsyncMessageHandler`.
+pub(crate) struct AsyncSession<'a, H: AsyncMessageHandler> {
+    config: &'a MilterConfig,
+    ctx: SessionContext,
+    message_handler: &'a mut H,
+}
+
+impl<'a, H: AsyncMessageHandler> AsyncSession<'a, H> {
+    async fn handle_message<S: AsyncWrite + Unpin + Send>(
+        &mut self,
+        s: &mut S,
+        buffer: &[u8],
+    ) -> Result<bool, MilterError> {
+        let mut keep_open = true;
+
+        match MilterMessage::try_from(buffer) {
+            Ok(message) => {
+                match message {
+                    MilterMessage::AbortFilterChecks => {
+                        self.message_handler.abort_filter_checks(&self.ctx).await;
+                        self.ctx.reset_message();
+                    }
+                    MilterMessage::BodyChunk { value } => {
+                        let action = self.message_handler.body_chunk_raw(&self.ctx, &value).await;
+                        self.send_reply(s, action, MilterProtocol::NO_REPLY_BODY)
+                            .await?;
+                    }
+                    MilterMessage::ConnectionInformation {
+                        hostname,
+                        family,
+                        port,
+                        address,
+                    } => {
+                        self.ctx
+                            .set_connection(ConnectionInfo::new(&hostname, family, port, &address));
+                        let action = self
+                            .message_handler
+                            .connection(&self.ctx, &hostname, &family, &port, &address)
+                            .await;
+                        self.send_reply(s, action, MilterProtocol::NO_REPLY_CONNECT)
+                            .await?;
+                    }
+                    MilterMessage::Data => {
+                        let action = self.message_handler.data(&self.ctx).await;
+                        self.send_reply(s, action, MilterProtocol::NO_REPLY_DATA)
+                            .await?;
+                    }
+                    MilterMessage::DefineMacros { cmdcode, macros } => {
+                        self.ctx.define_macros(cmdcode, macros.clone());
+                        self.message_handler
+                            .define_macros(&self.ctx, &cmdcode, macros)
+                            .await;
+                    }
+                    MilterMessage::EndOfBody => {
+                        let mut modifier = AsyncMessageModifier::new(
+                            s,
+                            self.ctx.actions(),
+                            self.config.write_timeout,
+                        );
+                        let action = match self
+                            .message_handler
+                            .end_of_body(&self.ctx, &mut modifier)
+                            .await
+                        {
+                            AcceptRejectAction::Skip => AcceptRejectAction::Continue,
+                            action => action,
+                        };
+                        self.ctx.reset_message();
+
+                        let response: ResponseMessage = action.into();
+                        self.write(s, response.get_content()).await?;
+                    }
+                    MilterMessage::EndOfHeader => {
+                        let action = self.message_handler.end_of_header(&self.ctx).await;
+                        self.send_reply(s, action, MilterProtocol::NO_REPLY_EOH)
+                            .await?;
+                    }
+                    MilterMessage::Header { header } => {
+                        self.ctx.add_header(header.clone());
+                        let action = self.message_handler.header_raw(&self.ctx, &header).await;
+                        self.send_reply(s, action, MilterProtocol::NO_REPLY_HEADER)
+                            .await?;
+                    }
+                    MilterMessage::Helo { msg } => {
+                        self.ctx.set_helo(&msg);
+                        let action = self.message_handler.helo(&self.ctx, &msg).await;
+                        self.send_reply(s, action, MilterProtocol::NO_REPLY_HELO)
+                            .await?;
+                    }
+                    MilterMessage::MailFrom { sender, args } => {
+                        self.ctx.set_sender(EnvelopeAddress::new(&sender, &args));
+                        let action = self
+                            .message_handler
+                            .mail_from(&self.ctx, &sender, &args)
+                            .await;
+                        self.send_reply(s, action, MilterProtocol::NO_REPLY_MAIL)
+                            .await?;
+                    }
+                    MilterMessage::OptionNegotiation {
+                        version,
+                        actions,
+                        protocol,
+                    } => match self.config.negotiate(version, actions, protocol) {
+                        Ok(options) => {
+                            let response = ResponseMessage::option_negotiation(&options)?;
+                            self.write(s, response.get_content()).await?;
+                            self.ctx.set_options(options);
+                        }
+                        Err(e) => {
+                            eprintln!("Option negotiation failed: {}", e);
+                            keep_open = false;
+                        }
+                    },
+                    MilterMessage::QuitCommunication => keep_open = false,
+                    MilterMessage::QuitNewConnection => {
+                        self.message_handler.close(&self.ctx).await;
+                        self.ctx.reset_connection();
+                    }
+                    MilterMessage::RecipientInformation { recipient, args } => {
+                        self.ctx
+                            .add_recipient(EnvelopeAddress::new(&recipient, &args));
+                        let action = self
+                            .message_handler
+                            .recipient(&self.ctx, &recipient, &args)
+                            .await;
+                        self.send_reply(s, action, MilterProtocol::NO_REPLY_RECIPIENT)
+                            .await?;
+                    }
+                    MilterMessage::UnknownCommand { command } => {
+                        let action = self
+                            .message_handler
+                            .unknown_command(&self.ctx, &command)
+                            .await;
+                        self.send_reply(s, action, MilterProtocol::NO_REPLY_UNKNOWN)
+                            .await?;
+                    }
+                };
+            }
+            Err(_e) => {
+                let response: ResponseMessage = AcceptRejectAction::Continue.into();
+                self.write(s, response.get_content()).await?;
+            }
+        }
+
+        Ok(keep_open)
+    }
+
+    /// Handles the connection until it ends or `closed` completes, whichever comes first.
+    pub(crate) async fn handle_stream<S, C>(
+        &mut self,
+        stream: S,
+        closed: C,
+    ) -> Result<(), MilterError>
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send,
+        C: Future<Output = ()>,
+    {
+        let result = tokio::select! {
+            result = self.receive(stream) => result,
+            _ = closed => Ok(()),
+        };
+
+        // Every way the connection ends (including timeouts and errors) leads here
+        self.message_handler.close(&self.ctx).await;
+
+        result
+    }
+
+    pub(crate) fn new(message_handler: &'a mut H, config: &'a MilterConfig) -> Self {
+        Self {
+            config,
+            ctx: SessionContext::new(),
+            message_handler,
+        }
+    }
+
+    /// Receives and handles the messages of the MTA until the connection is closed.
+    async fn receive<S: AsyncRead + AsyncWrite + Unpin + Send>(
+        &mut self,
+        mut stream: S,
+    ) -> Result<(), MilterError> {
+        let mut buffer = [0; 128];
+        let mut collected_bytes = Vec::new();
+
+        loop {
+            // Waiting for a new message is covered by the idle timeout, waiting for the rest of a
+            // started message by the read timeout
+            let timeout_kind = if collected_bytes.is_empty() {
+                TimeoutKind::Idle
+            } else {
+                TimeoutKind::Read
+            };
+
+            let read = with_timeout(self.config.timeout(timeout_kind), stream.read(&mut buffer));
+
+            match read.await {
+                Ok(0) => {
+                    println!("Closing connection");
+                    break;
+                }
+                Ok(len) => {
+                    collected_bytes.extend_from_slice(&buffer[..len]);
+
+                    while let Some(msg) = next_message(&mut collected_bytes)? {
+                        match self.handle_message(&mut stream, &msg).await {
+                            Ok(true) => {}
+                            Ok(false) => return Ok(()),
+                            Err(MilterError::IoError(e)) if is_timeout(&e) => {
+                                self.timed_out(TimeoutKind::Write).await;
+                                return Ok(());
+                            }
+                            Err(e) => return Err(e),
+                        }
+                    }
+                }
+                Err(e) if is_timeout(&e) => {
+                    self.timed_out(timeout_kind).await;
+                    break;
+                }
+                Err(e) => {
+                    eprintln!("Error while receiving data: {}", e);
+                    break;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Sends the reply of an `AsyncMessageHandler` method (see `reply_action`).
+    async fn send_reply<S: AsyncWrite + Unpin + Send>(
+        &mut self,
+        s: &mut S,
+        action: AcceptRejectAction,
+        no_reply: MilterProtocol,
+    ) -> Result<(), MilterError> {
+        match reply_action(self.ctx.options(), action, no_reply) {
+            Some(action) => {
+                let response: ResponseMessage = action.into();
+                self.write(s, response.get_content()).await
+            }
+            None => Ok(()),
+        }
+    }
+
+    async fn timed_out(&mut self, kind: TimeoutKind) {
+        eprintln!("Closing connection after {:?} timeout", kind);
+        self.message_handler.timeout(&self.ctx, kind).await;
+    }
+
+    /// Writes `bytes` to the MTA within the write timeout.
+    async fn write<S: AsyncWrite + Unpin + Send>(
+        &mut self,
+        s: &mut S,
+        bytes: &[u8],
+    ) -> Result<(), MilterError> {
+        let write = async {
+            s.write_all(bytes).await?;
+            s.flush().await
+        };
+
+        Ok(with_timeout(self.config.write_timeout, write).await?)
+    }
+}
+
+/// Runs `future` and fails with `ErrorKind::TimedOut` if it doesn't complete within `timeout`.
+pub(crate) async fn with_timeout<T, F>(timeout: Option<Duration>, future: F) -> std::io::Result<T>
+where
+    F: Future<Output = std::io::Result<T>>,
+{
+    match timeout {
+        Some(timeout) => tokio::time::timeout(timeout, future)
+            .await
+            .unwrap_or_else(|_| Err(std::io::ErrorKind::TimedOut.into())),
+        None => future.await,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct MyMessageHandler {
+        timeout: Option<TimeoutKind>,
+    }
+
+    impl AsyncMessageHandler for MyMessageHandler {
+        async fn timeout(&mut self, _ctx: &SessionContext, kind: TimeoutKind) {
+            self.timeout = Some(kind);
+        }
+
+        async fn header(
+            &mut self,
+            _ctx: &SessionContext,
+            name: &str,
+            _value: &str,
+        ) -> AcceptRejectAction {
+            match name {
+                "X-Spam" => AcceptRejectAction::Reject,
+                _ => AcceptRejectAction::Continue,
+            }
+        }
+    }
+
+    struct ProgressHandler {
+        checked: Option<tokio::sync::oneshot::Receiver<()>>,
+    }
+
+    impl AsyncMessageHandler for ProgressHandler {
+        async fn end_of_body(
+            &mut self,
+            _ctx: &SessionContext,
+            modifier: &mut AsyncMessageModifier<'_>,
+        ) -> AcceptRejectAction {
+            modifier.progress().await.unwrap();
+            // Wait until the MTA has received the progress notification
+            self.checked.take().unwrap().await.unwrap();
+            AcceptRejectAction::Accept
+        }
+    }
+
+    #[tokio::test]
+    async fn handle_stream_closes_idle_connection() {
+        let config = MilterConfig {
+            idle_timeout: Some(Duration::from_millis(50)),
+            ..MilterConfig::default()
+        };
+        let mut handler = MyMessageHandler { timeout: None };
+        let (mut mta, milter) = tokio::io::duplex(1024);
+
+        AsyncSession::new(&mut handler, &config)
+            .handle_stream(milter, std::future::pending())
+            .await
+            .unwrap();
+
+        assert_eq!(Some(TimeoutKind::Idle), handler.timeout);
+        assert_eq!(0, mta.read(&mut [0; 8]).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn handle_stream_replies_to_messages() {
+        let config = MilterConfig::default();
+        let mut handler = MyMessageHandler { timeout: None };
+        let (mut mta, milter) = tokio::io::duplex(1024);
+
+        let session = tokio::spawn(async move {
+            AsyncSession::new(&mut handler, &config)
+                .handle_stream(milter, std::future::pending())
+                .await
+        });
+
+        // Option negotiation (version 6, no actions, no protocol flags)
+        mta.write_all(&[0, 0, 0, 13, b'O', 0, 0, 0, 6, 0, 0, 0, 0, 0, 0, 0, 0])
+            .await
+            .unwrap();
+
+        let mut response = [0; 17];
+        mta.read_exact(&mut response).await.unwrap();
+        assert_eq!(&[0, 0, 0, 13, b'O', 0, 0, 0, 6][..], &response[..9]);
+
+        mta.write_all(b"\x00\x00\x00\x0cLX-Spam\x00yes\x00")
+            .await
+            .unwrap();
+
+        let mut response = [0; 5];
+        mta.read_exact(&mut response).await.unwrap();
+        assert_eq!(&[0, 0, 0, 1, b'r'], &response);
+
+        mta.write_all(&[0, 0, 0, 1, b'Q']).await.unwrap();
+        session.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn handle_stream_sends_progress_before_reply() {
+        let config = MilterConfig::default();
+        let (checked_tx, checked_rx) = tokio::sync::oneshot::channel();
+        let mut handler = ProgressHandler {
+            checked: Some(checked_rx),
+        };
+        let (mut mta, milter) = tokio::io::duplex(1024);
+
+        let session = tokio::spawn(async move {
+            AsyncSession::new(&mut handler, &config)
+                .handle_stream(milter, std::future::pending())
+                .await
+        });
+
+        mta.write_all(&[0, 0, 0, 13, b'O', 0, 0, 0, 6, 0, 0, 0, 0, 0, 0, 0, 0])
+            .await
+            .unwrap();
+        mta.read_exact(&mut [0; 17]).await.unwrap();
+
+        mta.write_all(&[0, 0, 0, 1, b'E']).await.unwrap();
+
+        let mut response = [0; 5];
+        mta.read_exact(&mut response).await.unwrap();
+        assert_eq!(&[0, 0, 0, 1, b'p'], &response);
+
+        checked_tx.send(()).unwrap();
+        mta.read_exact(&mut response).await.unwrap();
+        assert_eq!(&[0, 0, 0, 1, b'a'], &response);
+
+        mta.write_all(&[0, 0, 0, 1, b'Q']).await.unwrap();
+        session.await.unwrap().unwrap();
+    }
+}
@@ -0,0 +1,284 @@
+use charset::Charset;
+
+/// Decodes the RFC 2047 encoded words (e.g. `=?utf-8?Q?f=C3=BCr?=`) of a header value,
+/// including RFC 2231 language tags (e.g. `=?utf-8*de?Q?f=C3=BCr?=`).
+///
+/// Whitespace between adjacent encoded words is dropped, and adjacent encoded words using the
+/// same charset are decoded together, so characters split across encoded words are kept intact.
+/// Encoded words that can't be decoded (e.g. unknown charsets or broken encodings) are kept as
+/// they are. Invalid UTF-8 sequences outside of encoded words are replaced.
+///
+/// Returns `None` if the value doesn't contain any encoded words that could be decoded.
+///
+/// # Example:
+/// ```
+/// use rmilter::encoded_word;
+///
+/// let value = b"=?utf-8?Q?Gr=C3=BC=C3=9Fe?= =?iso-8859-1*de?Q?_=FCber?= rmilter";
+///
+/// assert_eq!(Some("Grüße über rmilter".into()), encoded_word::decode(value));
+/// assert_eq!(None, encoded_word::decode(b"plain"));
+/// ```
+pub fn decode(value: &[u8]) -> Option<String> {
+    let mut res = String::with_capacity(value.len());
+    // Decoded bytes of adjacent encoded words using the same charset
+    let mut pending: Option<(Charset, Vec<u8>)> = None;
+    let mut text_start = 0;
+    let mut pos = 0;
+
+    while pos < value.len() {
+        let word = match EncodedWord::parse(&value[pos..]) {
+            Some(word) => word,
+            None => {
+                pos += 1;
+                continue;
+            }
+        };
+
+        let text = &value[text_start..pos];
+        if pending.is_none() || !text.iter().all(u8::is_ascii_whitespace) {
+            flush(&mut res, &mut pending);
+            res.push_str(&String::from_utf8_lossy(text));
+        }
+
+        match &mut pending {
+            Some((charset, bytes)) if *charset == word.charset => bytes.extend(word.bytes),
+            _ => {
+                flush(&mut res, &mut pending);
+                pending = Some((word.charset, word.bytes));
+            }
+        }
+
+        pos += word.len;
+        text_start = pos;
+    }
+
+    if text_start == 0 {
+        return None;
+    }
+
+    flush(&mut res, &mut pending);
+    res.push_str(&String::from_utf8_lossy(&value[text_start..]));
+
+    Some(res)
+}
+
+/// Appends the pending decoded bytes (if any) to `res`.
+fn flush(res: &mut String, pending: &mut Option<(Charset, Vec<u8>)>) {
+    if let Some((charset, bytes)) = pending.take() {
+        let (decoded, _) = charset.decode_without_bom_handling(&bytes);
+        res.push_str(&decoded);
+    }
+}
+
+/// An encoded word (`=?charset[*language]?encoding?encoded-text?=`) with its decoded bytes.
+struct EncodedWord {
+    bytes: Vec<u8>,
+    charset: Charset,
+    len: usize,
+}
+
+impl EncodedWord {
+    /// Parses the encoded word at the start of `s`.
+    fn parse(s: &[u8]) -> Option<Self> {
+        let rest = s.strip_prefix(b"=?")?;
+
+        let charset_end = rest.iter().position(|b| *b == b'?')?;
+        let (charset, rest) = rest.split_at(charset_end);
+        // The language (RFC 2231) isn't needed for decoding
+        let charset = charset.split(|b| *b == b'*').next()?;
+        if charset.is_empty() || charset.iter().any(u8::is_ascii_whitespace) {
+            return None;
+        }
+        let charset = Charset::for_label_no_replacement(charset)?;
+
+        let (encoding, rest) = match rest {
+            [b'?', encoding, b'?', rest @ ..] => (encoding.to_ascii_uppercase(), rest),
+            _ => return None,
+        };
+
+        // The encoded text must not contain '?', so it ends at the first one
+        let text_end = rest.iter().position(|b| *b == b'?')?;
+        let text = &rest[..text_end];
+        if rest.get(text_end + 1) != Some(&b'=') || text.iter().any(u8::is_ascii_whitespace) {
+            return None;
+        }
+
+        let bytes = match encoding {
+            b'B' => {
+                // Missing padding is tolerated
+                let text: Vec<u8> = text.iter().copied().filter(|b| *b != b'=').collect();
+                base64::decode_config(text, base64::STANDARD_NO_PAD).ok()?
+            }
+            b'Q' => {
+                let text: Vec<u8> = text
+                    .iter()
+                    .map(|b| if *b == b'_' { b' ' } else { *b })
+                    .collect();
+                quoted_printable::decode(text, quoted_printable::ParseMode::Robust).ok()?
+            }
+            _ => return None,
+        };
+
+        Some(Self {
+            bytes,
+            charset,
+            // "=?" + charset + "?E?" + encoded text + "?="
+            len: s.len() - rest.len() + text_end + 2,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decode_str(s: &str) -> Option<String> {
+        decode(s.as_bytes())
+    }
+
+    #[test]
+    fn decode_utf8_base64() {
+        // Taken from an actual spam mail which contained padding chars
+        let input = "=?utf-8?B?IkjDtmhsZSBkZXIgTMO2d2VuIiBTeXN0ZW0gbWFjaHQgRGV1dHNjaGUgQsO8cmdlciByZWljaCE=?=";
+        let comp = "\"Höhle der Löwen\" System macht Deutsche Bürger reich!";
+
+        assert_eq!(Some(comp.into()), decode_str(input));
+    }
+
+    #[test]
+    fn decode_utf8_base64_with_not_encoded() {
+        // Taken from an actual spam mail and added 'not encoded' to test that we keep non-encoded
+        // data
+        let input = "not encoded=?utf-8?B?4oCeSMO2aGxlIGRlciBMw7Z3ZW7igJwgU3lzdGVtIG1hY2h0IERldXRzY2hlIELDvHJnZXIgcmVpY2gh?=not encoded";
+        let comp = "not encoded„Höhle der Löwen“ System macht Deutsche Bürger reich!not encoded";
+
+        assert_eq!(Some(comp.into()), decode_str(input));
+    }
+
+    /// Used for testing that we keep the original input with broken encoding
+    #[test]
+    fn decode_utf8_base64_broken_encoding() {
+        let input =
+            "not encoded=?utf-8?B?w7Z3ZW7igJ2h0IERldXRzY2hlIELDvHJnZXIgcmVpY2gh?=not encoded";
+
+        assert_eq!(None, decode_str(input));
+    }
+
+    #[test]
+    fn decode_utf8_base64_without_padding() {
+        assert_eq!(Some("für".into()), decode_str("=?utf-8?B?ZsO8cg?="));
+    }
+
+    #[test]
+    fn decode_utf8_quoted_printable() {
+        let input = "=?utf-8?Q?Endlich_was_extrem_hartes_f=C3=BCr_Sie.?=";
+        let comp = "Endlich was extrem hartes für Sie.";
+
+        assert_eq!(Some(comp.into()), decode_str(input));
+    }
+
+    #[test]
+    fn decode_rfc2047_examples() {
+        // Taken from RFC 2047, section 8
+        let vectors = [
+            ("(=?ISO-8859-1?Q?a?=)", "(a)"),
+            ("(=?ISO-8859-1?Q?a?= b)", "(a b)"),
+            ("(=?ISO-8859-1?Q?a?= =?ISO-8859-1?Q?b?=)", "(ab)"),
+            ("(=?ISO-8859-1?Q?a?=  =?ISO-8859-1?Q?b?=)", "(ab)"),
+            ("(=?ISO-8859-1?Q?a?=\r\n    =?ISO-8859-1?Q?b?=)", "(ab)"),
+            ("(=?ISO-8859-1?Q?a_b?=)", "(a b)"),
+            ("(=?ISO-8859-1?Q?a?= =?ISO-8859-2?Q?_b?=)", "(a b)"),
+            (
+                "=?ISO-8859-1?Q?Andr=E9?= Pirard <PIRARD@vm1.ulg.ac.be>",
+                "André Pirard <PIRARD@vm1.ulg.ac.be>",
+            ),
+            (
+                "=?US-ASCII?Q?Keith_Moore?= <moore@cs.utk.edu>",
+                "Keith Moore <moore@cs.utk.edu>",
+            ),
+        ];
+
+        for (input, comp) in &vectors {
+            assert_eq!(Some(String::from(*comp)), decode_str(input), "{}", input);
+        }
+    }
+
+    #[test]
+    fn decode_rfc2231_language() {
+        assert_eq!(
+            Some("Keith Moore".into()),
+            decode_str("=?US-ASCII*EN?Q?Keith_Moore?=")
+        );
+        assert_eq!(
+            Some("für dich".into()),
+            decode_str("=?utf-8*de-DE?B?ZsO8cg==?= =?UTF-8*de?Q?_dich?=")
+        );
+    }
+
+    #[test]
+    fn decode_multiple_words_not_greedy() {
+        assert_eq!(
+            Some("a und b".into()),
+            decode_str("=?utf-8?Q?a?= und =?utf-8?Q?b?=")
+        );
+        assert_eq!(
+            Some("Grüße, für Sie".into()),
+            decode_str("=?utf-8?Q?Gr=C3=BC=C3=9Fe?=, =?utf-8?B?ZsO8cg==?= Sie")
+        );
+    }
+
+    #[test]
+    fn decode_character_split_across_words() {
+        // 'ü' (0xC3 0xBC) is split into two encoded words
+        assert_eq!(
+            Some("für".into()),
+            decode_str("=?utf-8?Q?f=C3?= =?utf-8?Q?=BCr?=")
+        );
+        assert_eq!(
+            Some("für".into()),
+            decode_str("=?utf-8?B?ZsM=?=\r\n =?utf-8?B?vHI=?=")
+        );
+    }
+
+    #[test]
+    fn decode_non_ascii_prefix() {
+        assert_eq!(
+            Some("Grüße für dich".into()),
+            decode_str("Grüße =?utf-8?Q?f=C3=BCr?= dich")
+        );
+        assert_eq!(
+            Some("\u{fffd} für".into()),
+            decode(b"\xff =?utf-8?Q?f=C3=BCr?=")
+        );
+    }
+
+    #[test]
+    fn decode_invalid_words_are_kept() {
+        let vectors = [
+            "=?unknown?Q?a?=",
+            "=?utf-8?X?a?=",
+            "=??Q?a?=",
+            "=?utf-8?Q?a b?=",
+            "=?utf-8?Q?a?b?=",
+            "=?utf-8?Q?a",
+            "=?utf-8?Q?a?",
+        ];
+
+        for input in &vectors {
+            assert_eq!(None, decode_str(input), "{}", input);
+        }
+
+        // Whitespace next to words that can't be decoded is kept
+        assert_eq!(
+            Some("a =?unknown?Q?b?= c".into()),
+            decode_str("=?utf-8?Q?a?= =?unknown?Q?b?= =?utf-8?Q?c?=")
+        );
+    }
+
+    #[test]
+    fn decode_without_encoded_words() {
+        assert_eq!(None, decode_str(""));
+        assert_eq!(None, decode_str("plain = value?"));
+    }
+}
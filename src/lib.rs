@@ -3,14 +3,20 @@
 //!
 //! **rmilter** is a Rust crate that allows to connect to MTA services like sendmail or postfix using the milter protocol.
 //!
-//! This library uses pure safe Rust code and doesn't require external libraries like libmilter.
+//! This library uses pure safe Rust code (except for adopting sockets passed by systemd) and doesn't require external libraries like libmilter.
 //!
 //! Features
 //! --------
 //!
-//! - Connect to MTA services using the milter protocol (IPv4/IPv6 only for now)
+//! - Connect to MTA services using the milter protocol (IPv4/IPv6 and unix domain sockets)
 //! - Define which messages should be transferred
-//! - Automatically decode `base64` and `quoted-printable` values
+//! - Handle connections concurrently using a `MessageHandler` per connection
+//! - Session state (connection, envelope, headers and macros) collected in a `SessionContext`
+//! - Graceful shutdown that lets running sessions finish
+//! - systemd socket activation and readiness notification
+//! - Optional async support on the tokio runtime using an `AsyncMessageHandler` (`tokio` feature)
+//! - Automatically decode RFC 2047/2231 encoded words (`base64` and `quoted-printable`)
+//! - Modify messages at the end of the body
 //! - Uses Rust's type system to prevent misusing the milter protocol
 //!
 //! Usage
@@ -31,12 +37,13 @@
 //! use rmilter::message_handler::MessageHandler;
 //! use rmilter::milter_message::MilterProtocol;
 //! use rmilter::milter_builder::MilterBuilder;
+//! use rmilter::session_context::SessionContext;
 //!
 //! struct MyMessageHandler {}
 //!
 //! impl MessageHandler for MyMessageHandler {
-//!     fn header(&mut self, name: &str, value: &str) -> AcceptRejectAction {
-//!         println!("name: {}, value: {}", name, value);
+//!     fn header(&mut self, ctx: &SessionContext, name: &str, value: &str) -> AcceptRejectAction {
+//!         println!("message: {}, name: {}, value: {}", ctx.message_id(), name, value);
 //!         AcceptRejectAction::Continue
 //!     }
 //! }
@@ -59,7 +66,7 @@
 //!
 //! **rmilter** can be used to connect to MTA services and receive messages. It is also possible to easily accept or reject a mail (using AcceptRejectAction).
 //!
-//! Currently, functionality for manipulating the mail (add header, recipients and so on) is not yet supported, but will be in a future release.
+//! Headers, the sender, recipients and the body of a mail can be modified at the end of the body (using MessageModifier).
 
 // Set proper hmtl root for docs.rs
 #![doc(html_root_url = "https://docs.rs/rmilter/0.1.0")]
@@ -67,12 +74,28 @@
 #[macro_use]
 extern crate bitflags;
 
-#[macro_use]
-extern crate lazy_static;
-
 pub mod accept_reject_action;
+#[cfg(feature = "tokio")]
+pub mod async_message_handler;
+#[cfg(feature = "tokio")]
+pub mod async_message_modifier;
+#[cfg(feature = "tokio")]
+pub mod async_milter;
+#[cfg(feature = "tokio")]
+pub mod async_milter_builder;
+#[cfg(feature = "tokio")]
+mod async_session;
+pub mod encoded_word;
+pub mod macro_store;
 pub mod message_handler;
+pub mod message_modifier;
 pub mod milter;
 pub mod milter_builder;
 pub mod milter_error;
 pub mod milter_message;
+pub mod milter_socket;
+mod session;
+pub mod session_context;
+pub mod shutdown_handle;
+#[cfg(unix)]
+pub mod systemd;
@@ -0,0 +1,316 @@
+use std::net::IpAddr;
+
+use crate::milter_message::MilterMacro;
+
+/// Collects the macros defined by the MTA during a connection.
+///
+/// The macros are stored by the command they have been defined for (the `cmdcode` passed to
+/// `MessageHandler::define_macros`). Defining the macros of a command again replaces its
+/// previous macros, e.g. the macros of the previous recipient.
+///
+/// Lookups search the most recently defined command first, so the latest value of a macro
+/// defined for several commands is returned.
+///
+/// The macros of the current connection and message are available using
+/// `SessionContext::macros`.
+///
+/// # Example
+/// ```
+/// use rmilter::accept_reject_action::AcceptRejectAction;
+/// use rmilter::message_handler::MessageHandler;
+/// use rmilter::session_context::SessionContext;
+///
+/// struct MyMessageHandler {}
+///
+/// impl MessageHandler for MyMessageHandler {
+///     fn end_of_header(&mut self, ctx: &SessionContext) -> AcceptRejectAction {
+///         let macros = ctx.macros();
+///
+///         println!("queue id: {:?}, user: {:?}", macros.queue_id(), macros.auth_user());
+///         AcceptRejectAction::Continue
+///     }
+/// }
+/// ```
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct MacroStore {
+    stages: Vec<(char, Vec<MilterMacro>)>,
+}
+
+impl MacroStore {
+    /// Returns the name of the authenticated user (`{auth_authen}`).
+    ///
+    /// # Example:
+    /// ```
+    /// use rmilter::macro_store::MacroStore;
+    /// use rmilter::milter_message::MilterMacro;
+    ///
+    /// let mut macros = MacroStore::new();
+    /// macros.define('M', vec![MilterMacro::new("{auth_authen}", "alice")]);
+    ///
+    /// assert_eq!(Some("alice"), macros.auth_user());
+    /// ```
+    pub fn auth_user(&self) -> Option<&str> {
+        self.get("auth_authen")
+    }
+
+    /// Removes the macros defined for the current message, keeping the macros of the connection
+    /// (SMFIC_CONNECT) and HELO (SMFIC_HELO) commands.
+    ///
+    /// # Example:
+    /// ```
+    /// use rmilter::macro_store::MacroStore;
+    /// use rmilter::milter_message::MilterMacro;
+    ///
+    /// let mut macros = MacroStore::new();
+    /// macros.define('C', vec![MilterMacro::new("{daemon_name}", "smtpd")]);
+    /// macros.define('M', vec![MilterMacro::new("i", "4FDB12C0A3")]);
+    ///
+    /// macros.clear_message();
+    ///
+    /// assert_eq!(Some("smtpd"), macros.daemon_name());
+    /// assert_eq!(None, macros.queue_id());
+    /// ```
+    pub fn clear_message(&mut self) {
+        self.stages
+            .retain(|(cmdcode, _)| *cmdcode == 'C' || *cmdcode == 'H');
+    }
+
+    /// Returns the IP address of the SMTP client (`{client_addr}`).
+    ///
+    /// IPv6 addresses prefixed with `IPv6:` (as sent by sendmail) are supported as well.
+    ///
+    /// # Example:
+    /// ```
+    /// use std::net::{IpAddr, Ipv4Addr};
+    ///
+    /// use rmilter::macro_store::MacroStore;
+    /// use rmilter::milter_message::MilterMacro;
+    ///
+    /// let mut macros = MacroStore::new();
+    /// macros.define('C', vec![MilterMacro::new("{client_addr}", "192.0.2.1")]);
+    ///
+    /// assert_eq!(Some(IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1))), macros.client_address());
+    /// ```
+    pub fn client_address(&self) -> Option<IpAddr> {
+        let address = self.get("client_addr")?;
+
+        address
+            .strip_prefix("IPv6:")
+            .unwrap_or(address)
+            .parse()
+            .ok()
+    }
+
+    /// Returns the name of the MTA daemon (`{daemon_name}`).
+    ///
+    /// # Example:
+    /// ```
+    /// use rmilter::macro_store::MacroStore;
+    /// use rmilter::milter_message::MilterMacro;
+    ///
+    /// let mut macros = MacroStore::new();
+    /// macros.define('C', vec![MilterMacro::new("{daemon_name}", "smtpd")]);
+    ///
+    /// assert_eq!(Some("smtpd"), macros.daemon_name());
+    /// ```
+    pub fn daemon_name(&self) -> Option<&str> {
+        self.get("daemon_name")
+    }
+
+    /// Stores the macros defined for the command `cmdcode`, replacing its previous macros.
+    ///
+    /// # Example:
+    /// ```
+    /// use rmilter::macro_store::MacroStore;
+    /// use rmilter::milter_message::MilterMacro;
+    ///
+    /// let mut macros = MacroStore::new();
+    /// macros.define('R', vec![MilterMacro::new("{rcpt_addr}", "bob@example.org")]);
+    /// macros.define('R', vec![MilterMacro::new("{rcpt_addr}", "carol@example.org")]);
+    ///
+    /// assert_eq!(Some("carol@example.org"), macros.get("{rcpt_addr}"));
+    /// ```
+    pub fn define(&mut self, cmdcode: char, macros: Vec<MilterMacro>) {
+        self.stages.retain(|(c, _)| *c != cmdcode);
+        self.stages.push((cmdcode, macros));
+    }
+
+    /// Returns the value of the macro `name`, which can be given with or without braces (e.g.
+    /// `i`, `{i}`, `auth_authen` or `{auth_authen}`).
+    ///
+    /// # Example:
+    /// ```
+    /// use rmilter::macro_store::MacroStore;
+    /// use rmilter::milter_message::MilterMacro;
+    ///
+    /// let mut macros = MacroStore::new();
+    /// macros.define('C', vec![MilterMacro::new("j", "mx.example.org")]);
+    ///
+    /// assert_eq!(Some("mx.example.org"), macros.get("j"));
+    /// assert_eq!(Some("mx.example.org"), macros.get("{j}"));
+    /// assert_eq!(None, macros.get("{client_name}"));
+    /// ```
+    pub fn get(&self, name: &str) -> Option<&str> {
+        let name = strip_braces(name);
+
+        self.stages
+            .iter()
+            .rev()
+            .flat_map(|(_, macros)| macros.iter())
+            .find(|m| strip_braces(m.name()) == name)
+            .map(MilterMacro::value)
+    }
+
+    /// Creates an empty MacroStore.
+    ///
+    /// # Example:
+    /// ```
+    /// use rmilter::macro_store::MacroStore;
+    ///
+    /// let macros = MacroStore::new();
+    ///
+    /// assert!(macros.stage('C').is_empty());
+    /// ```
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns the queue id of the message (`i`).
+    ///
+    /// # Example:
+    /// ```
+    /// use rmilter::macro_store::MacroStore;
+    /// use rmilter::milter_message::MilterMacro;
+    ///
+    /// let mut macros = MacroStore::new();
+    /// macros.define('M', vec![MilterMacro::new("i", "4FDB12C0A3")]);
+    ///
+    /// assert_eq!(Some("4FDB12C0A3"), macros.queue_id());
+    /// ```
+    pub fn queue_id(&self) -> Option<&str> {
+        self.get("i")
+    }
+
+    /// Returns the macros defined for the command `cmdcode`.
+    ///
+    /// # Example:
+    /// ```
+    /// use rmilter::macro_store::MacroStore;
+    /// use rmilter::milter_message::MilterMacro;
+    ///
+    /// let mut macros = MacroStore::new();
+    /// macros.define('H', vec![MilterMacro::new("{tls_version}", "TLSv1.3")]);
+    ///
+    /// assert_eq!(1, macros.stage('H').len());
+    /// assert!(macros.stage('M').is_empty());
+    /// ```
+    pub fn stage(&self, cmdcode: char) -> &[MilterMacro] {
+        self.stages
+            .iter()
+            .find(|(c, _)| *c == cmdcode)
+            .map(|(_, macros)| macros.as_slice())
+            .unwrap_or(&[])
+    }
+
+    /// Returns the TLS version used by the SMTP client (`{tls_version}`).
+    ///
+    /// # Example:
+    /// ```
+    /// use rmilter::macro_store::MacroStore;
+    /// use rmilter::milter_message::MilterMacro;
+    ///
+    /// let mut macros = MacroStore::new();
+    /// macros.define('H', vec![MilterMacro::new("{tls_version}", "TLSv1.3")]);
+    ///
+    /// assert_eq!(Some("TLSv1.3"), macros.tls_version());
+    /// ```
+    pub fn tls_version(&self) -> Option<&str> {
+        self.get("tls_version")
+    }
+}
+
+/// Returns the macro name without surrounding braces.
+fn strip_braces(name: &str) -> &str {
+    name.strip_prefix('{')
+        .and_then(|name| name.strip_suffix('}'))
+        .unwrap_or(name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn get_prefers_latest_stage() {
+        let mut macros = MacroStore::new();
+        macros.define('C', vec![MilterMacro::new("{auth_authen}", "")]);
+        macros.define('M', vec![MilterMacro::new("{auth_authen}", "alice")]);
+
+        assert_eq!(Some("alice"), macros.auth_user());
+
+        macros.define('C', vec![MilterMacro::new("{auth_authen}", "bob")]);
+
+        assert_eq!(Some("bob"), macros.auth_user());
+    }
+
+    #[test]
+    fn get_with_and_without_braces() {
+        let mut macros = MacroStore::new();
+        macros.define(
+            'M',
+            vec![
+                MilterMacro::new("{i}", "4FDB12C0A3"),
+                MilterMacro::new("{mail_addr}", "alice@example.org"),
+            ],
+        );
+
+        assert_eq!(Some("4FDB12C0A3"), macros.queue_id());
+        assert_eq!(Some("4FDB12C0A3"), macros.get("{i}"));
+        assert_eq!(Some("alice@example.org"), macros.get("mail_addr"));
+        assert_eq!(None, macros.get("{mail_addr"));
+    }
+
+    #[test]
+    fn client_address_ipv6() {
+        let mut macros = MacroStore::new();
+        macros.define(
+            'C',
+            vec![MilterMacro::new("{client_addr}", "IPv6:2001:db8::1")],
+        );
+
+        assert_eq!(
+            Some("2001:db8::1".parse().unwrap()),
+            macros.client_address()
+        );
+
+        macros.define('C', vec![MilterMacro::new("{client_addr}", "2001:db8::2")]);
+
+        assert_eq!(
+            Some("2001:db8::2".parse().unwrap()),
+            macros.client_address()
+        );
+
+        macros.define('C', vec![MilterMacro::new("{client_addr}", "unknown")]);
+
+        assert_eq!(None, macros.client_address());
+    }
+
+    #[test]
+    fn clear_message_keeps_connection_macros() {
+        let mut macros = MacroStore::new();
+        macros.define('C', vec![MilterMacro::new("j", "mx.example.org")]);
+        macros.define('H', vec![MilterMacro::new("{tls_version}", "TLSv1.3")]);
+        macros.define('M', vec![MilterMacro::new("i", "4FDB12C0A3")]);
+        macros.define(
+            'R',
+            vec![MilterMacro::new("{rcpt_addr}", "bob@example.org")],
+        );
+
+        macros.clear_message();
+
+        assert_eq!(Some("mx.example.org"), macros.get("j"));
+        assert_eq!(Some("TLSv1.3"), macros.tls_version());
+        assert!(macros.stage('M').is_empty());
+        assert!(macros.stage('R').is_empty());
+    }
+}
@@ -1,51 +1,129 @@
 use crate::accept_reject_action::AcceptRejectAction;
-use crate::milter_message::{MilterMacro, ProtocolFamily};
+use crate::message_modifier::MessageModifier;
+use crate::milter_message::{MilterHeader, MilterMacro, ProtocolFamily};
+use crate::session_context::SessionContext;
+
+/// Defines which timeout caused a connection to be closed (see `MilterBuilder::set_idle_timeout`,
+/// `MilterBuilder::set_read_timeout` and `MilterBuilder::set_write_timeout`).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TimeoutKind {
+    /// The MTA didn't send a new message in time
+    Idle,
+    /// The MTA didn't send the rest of a started message in time
+    Read,
+    /// A reply couldn't be sent to the MTA in time
+    Write,
+}
 
 /// Implement this trait to define the behavior of your milter application.
 ///
 /// All methods have a default implementation which returns AcceptRejectAction::Continue. Overwrite
 /// any of these methods to implement the desired behavior.
+///
+/// Each method receives the `SessionContext` of the connection, which contains the connection
+/// information, envelope, headers and macros received so far.
 pub trait MessageHandler {
     /// Milter checks for the current message have been aborted (SMFIC_ABORT).
     ///
+    /// `ctx` still contains the aborted message, its message part is reset afterwards.
+    ///
     /// # Example:
     /// ```
     /// use rmilter::message_handler::MessageHandler;
     /// use rmilter::milter_message::MilterMacro;
+    /// use rmilter::session_context::SessionContext;
     ///
     /// struct MyMessageHandler {}
     ///
     /// impl MessageHandler for MyMessageHandler {
-    ///     fn abort_filter_checks(&mut self) {
-    ///         println!("Aborted filter checks");
+    ///     fn abort_filter_checks(&mut self, ctx: &SessionContext) {
+    ///         println!("Aborted message {}", ctx.message_id());
     ///     }
     /// }
     /// ```
-    fn abort_filter_checks(&mut self) {}
+    #[allow(unused_variables)]
+    fn abort_filter_checks(&mut self, ctx: &SessionContext) {}
 
     /// A body chunk of the incoming email (SMFIC_BODY).
     ///
-    /// - `value` contains the value of the body chunk.
+    /// - `value` contains the value of the body chunk (see `body_chunk_raw` for the exact bytes).
+    ///
+    /// Return `AcceptRejectAction::Skip` to skip the remaining body chunks (requires an MTA
+    /// supporting `MilterProtocol::SKIP`).
     ///
     /// # Example:
     /// ```
     /// use rmilter::accept_reject_action::AcceptRejectAction;
     /// use rmilter::message_handler::MessageHandler;
+    /// use rmilter::session_context::SessionContext;
     ///
     /// struct MyMessageHandler {}
     ///
     /// impl MessageHandler for MyMessageHandler {
-    ///     fn body_chunk(&mut self, value: &str) -> AcceptRejectAction {
+    ///     fn body_chunk(&mut self, ctx: &SessionContext, value: &str) -> AcceptRejectAction {
     ///         println!("value: {}", value);
     ///         AcceptRejectAction::Continue
     ///     }
     /// }
     /// ```
     #[allow(unused_variables)]
-    fn body_chunk(&mut self, value: &str) -> AcceptRejectAction {
+    fn body_chunk(&mut self, ctx: &SessionContext, value: &str) -> AcceptRejectAction {
         AcceptRejectAction::Continue
     }
 
+    /// A body chunk of the incoming email with the exact bytes sent by the MTA (SMFIC_BODY).
+    ///
+    /// - `value` contains the bytes of the body chunk.
+    ///
+    /// Overwrite this method instead of `body_chunk` to process binary content or 8-bit
+    /// charsets. By default, the bytes are converted to a string (invalid UTF-8 sequences are
+    /// replaced) and passed to `body_chunk`.
+    ///
+    /// # Example:
+    /// ```
+    /// use rmilter::accept_reject_action::AcceptRejectAction;
+    /// use rmilter::message_handler::MessageHandler;
+    /// use rmilter::session_context::SessionContext;
+    ///
+    /// struct MyMessageHandler {
+    ///     body_size: usize,
+    /// }
+    ///
+    /// impl MessageHandler for MyMessageHandler {
+    ///     fn body_chunk_raw(&mut self, ctx: &SessionContext, value: &[u8]) -> AcceptRejectAction {
+    ///         self.body_size += value.len();
+    ///         AcceptRejectAction::Continue
+    ///     }
+    /// }
+    /// ```
+    fn body_chunk_raw(&mut self, ctx: &SessionContext, value: &[u8]) -> AcceptRejectAction {
+        self.body_chunk(ctx, &String::from_utf8_lossy(value))
+    }
+
+    /// The connection is closed or will be reused for a new SMTP session (SMFIC_QUIT_NC).
+    ///
+    /// This is called exactly once when the connection ends, no matter why (SMFIC_QUIT, the MTA
+    /// closing the connection, a failed option negotiation, a timeout or an error). Reset any
+    /// per-connection state here.
+    ///
+    /// # Example:
+    /// ```
+    /// use rmilter::message_handler::MessageHandler;
+    /// use rmilter::session_context::SessionContext;
+    ///
+    /// struct MyMessageHandler {
+    ///     helo: Option<String>,
+    /// }
+    ///
+    /// impl MessageHandler for MyMessageHandler {
+    ///     fn close(&mut self, ctx: &SessionContext) {
+    ///         self.helo = None;
+    ///     }
+    /// }
+    /// ```
+    #[allow(unused_variables)]
+    fn close(&mut self, ctx: &SessionContext) {}
+
     /// Provides information about the connection to the MTA (SMFIC_CONNECT).
     ///
     /// - `hostname` The hostname of the machine running the MTA.
@@ -58,12 +136,14 @@ pub trait MessageHandler {
     /// use rmilter::accept_reject_action::AcceptRejectAction;
     /// use rmilter::message_handler::MessageHandler;
     /// use rmilter::milter_message::ProtocolFamily;
+    /// use rmilter::session_context::SessionContext;
     ///
     /// struct MyMessageHandler {}
     ///
     /// impl MessageHandler for MyMessageHandler {
     ///     fn connection(
     ///     &mut self,
+    ///     ctx: &SessionContext,
     ///     hostname: &str,
     ///     family: &ProtocolFamily,
     ///     port: &u16,
@@ -80,6 +160,7 @@ pub trait MessageHandler {
     #[allow(unused_variables)]
     fn connection(
         &mut self,
+        ctx: &SessionContext,
         hostname: &str,
         family: &ProtocolFamily,
         port: &u16,
@@ -88,45 +169,89 @@ pub trait MessageHandler {
         AcceptRejectAction::Continue
     }
 
+    /// The client sent the DATA command (SMFIC_DATA).
+    ///
+    /// Can be disabled with `MilterProtocol::NO_DATA`.
+    ///
+    /// # Example:
+    /// ```
+    /// use rmilter::accept_reject_action::AcceptRejectAction;
+    /// use rmilter::message_handler::MessageHandler;
+    /// use rmilter::session_context::SessionContext;
+    ///
+    /// struct MyMessageHandler {}
+    ///
+    /// impl MessageHandler for MyMessageHandler {
+    ///     fn data(&mut self, ctx: &SessionContext) -> AcceptRejectAction {
+    ///         println!("Data");
+    ///         AcceptRejectAction::Continue
+    ///     }
+    /// }
+    /// ```
+    #[allow(unused_variables)]
+    fn data(&mut self, ctx: &SessionContext) -> AcceptRejectAction {
+        AcceptRejectAction::Continue
+    }
+
     /// A set of macros defined by the MTA (SMFIC_MACRO).
     ///
     /// - `cmdcode` represents the command for which the macros are defined.
-    /// - `macros` contains the defined macros.
+    /// - `macros` contains the defined macros (see `SessionContext::macros` for all macros).
     ///
     /// # Example:
     /// ```
     /// use rmilter::message_handler::MessageHandler;
     /// use rmilter::milter_message::MilterMacro;
+    /// use rmilter::session_context::SessionContext;
     ///
     /// struct MyMessageHandler {}
     ///
     /// impl MessageHandler for MyMessageHandler {
-    ///     fn define_macros(&mut self, cmdcode: &char, macros: Vec<MilterMacro>) {
-    ///         println!("cmdcode: {}", cmdcode);
+    ///     fn define_macros(
+    ///         &mut self,
+    ///         ctx: &SessionContext,
+    ///         cmdcode: &char,
+    ///         macros: Vec<MilterMacro>,
+    ///     ) {
+    ///         for m in macros {
+    ///             println!("cmdcode: {}, {}={}", cmdcode, m.name(), m.value());
+    ///         }
     ///     }
     /// }
     /// ```
     #[allow(unused_variables)]
-    fn define_macros(&mut self, cmdcode: &char, macros: Vec<MilterMacro>) {}
+    fn define_macros(&mut self, ctx: &SessionContext, cmdcode: &char, macros: Vec<MilterMacro>) {}
 
     /// The MTA informs that all body chunks of the message are sent (SMFIC_BODYEOB).
     ///
+    /// - `modifier` can be used to modify the message before the returned action is sent.
+    ///
     /// # Example:
     /// ```
     /// use rmilter::accept_reject_action::AcceptRejectAction;
     /// use rmilter::message_handler::MessageHandler;
+    /// use rmilter::message_modifier::MessageModifier;
+    /// use rmilter::session_context::SessionContext;
     ///
     /// struct MyMessageHandler {}
     ///
     /// impl MessageHandler for MyMessageHandler {
-    ///     fn end_of_body(&mut self) -> AcceptRejectAction {
+    ///     fn end_of_body(
+    ///         &mut self,
+    ///         ctx: &SessionContext,
+    ///         modifier: &mut MessageModifier,
+    ///     ) -> AcceptRejectAction {
     ///         println!("End of body");
     ///         AcceptRejectAction::Continue
     ///     }
     /// }
     /// ```
-    fn end_of_body(&mut self) -> AcceptRejectAction {
-        // TODO: Add support for modifying here (header, body, recipients, etc.)
+    #[allow(unused_variables)]
+    fn end_of_body(
+        &mut self,
+        ctx: &SessionContext,
+        modifier: &mut MessageModifier,
+    ) -> AcceptRejectAction {
         AcceptRejectAction::Continue
     }
 
@@ -136,44 +261,83 @@ pub trait MessageHandler {
     /// ```
     /// use rmilter::accept_reject_action::AcceptRejectAction;
     /// use rmilter::message_handler::MessageHandler;
+    /// use rmilter::session_context::SessionContext;
     ///
     /// struct MyMessageHandler {}
     ///
     /// impl MessageHandler for MyMessageHandler {
-    ///     fn end_of_header(&mut self) -> AcceptRejectAction {
+    ///     fn end_of_header(&mut self, ctx: &SessionContext) -> AcceptRejectAction {
     ///         println!("End of header");
     ///         AcceptRejectAction::Continue
     ///     }
     /// }
     /// ```
-    fn end_of_header(&mut self) -> AcceptRejectAction {
+    #[allow(unused_variables)]
+    fn end_of_header(&mut self, ctx: &SessionContext) -> AcceptRejectAction {
         AcceptRejectAction::Continue
     }
 
     /// A header chunk (SMFIC_HEADER).
     ///
     /// - `name` defines the name of the provided value.
-    /// - `value` contains the actual value.
+    /// - `value` contains the actual value (see `header_raw` for the exact bytes).
     ///
     /// # Example:
     /// ```
     /// use rmilter::accept_reject_action::AcceptRejectAction;
     /// use rmilter::message_handler::MessageHandler;
+    /// use rmilter::session_context::SessionContext;
     ///
     /// struct MyMessageHandler {}
     ///
     /// impl MessageHandler for MyMessageHandler {
-    ///     fn header(&mut self, name: &str, value: &str) -> AcceptRejectAction {
+    ///     fn header(
+    ///         &mut self,
+    ///         ctx: &SessionContext,
+    ///         name: &str,
+    ///         value: &str,
+    ///     ) -> AcceptRejectAction {
     ///         println!("name: {}, value: {}", name, value);
     ///         AcceptRejectAction::Continue
     ///     }
     /// }
     /// ```
     #[allow(unused_variables)]
-    fn header(&mut self, name: &str, value: &str) -> AcceptRejectAction {
+    fn header(&mut self, ctx: &SessionContext, name: &str, value: &str) -> AcceptRejectAction {
         AcceptRejectAction::Continue
     }
 
+    /// A header chunk including the exact bytes sent by the MTA (SMFIC_HEADER).
+    ///
+    /// - `header` contains the name, the raw value and the decoded value.
+    ///
+    /// Overwrite this method instead of `header` if the exact bytes of the value are needed
+    /// (e.g. for DKIM). By default, the name and the decoded value are passed to `header`.
+    ///
+    /// # Example:
+    /// ```
+    /// use rmilter::accept_reject_action::AcceptRejectAction;
+    /// use rmilter::message_handler::MessageHandler;
+    /// use rmilter::milter_message::MilterHeader;
+    /// use rmilter::session_context::SessionContext;
+    ///
+    /// struct MyMessageHandler {}
+    ///
+    /// impl MessageHandler for MyMessageHandler {
+    ///     fn header_raw(
+    ///         &mut self,
+    ///         ctx: &SessionContext,
+    ///         header: &MilterHeader,
+    ///     ) -> AcceptRejectAction {
+    ///         println!("name: {}, raw value: {:?}", header.name(), header.raw_value());
+    ///         AcceptRejectAction::Continue
+    ///     }
+    /// }
+    /// ```
+    fn header_raw(&mut self, ctx: &SessionContext, header: &MilterHeader) -> AcceptRejectAction {
+        self.header(ctx, header.name(), header.value())
+    }
+
     /// A helo message (SMFIC_HELO).
     ///
     /// - `msg` contains the sent helo message.
@@ -182,18 +346,19 @@ pub trait MessageHandler {
     /// ```
     /// use rmilter::accept_reject_action::AcceptRejectAction;
     /// use rmilter::message_handler::MessageHandler;
+    /// use rmilter::session_context::SessionContext;
     ///
     /// struct MyMessageHandler {}
     ///
     /// impl MessageHandler for MyMessageHandler {
-    ///     fn helo(&mut self, msg: &str) -> AcceptRejectAction {
+    ///     fn helo(&mut self, ctx: &SessionContext, msg: &str) -> AcceptRejectAction {
     ///         println!("msg: {}", msg);
     ///         AcceptRejectAction::Continue
     ///     }
     /// }
     /// ```
     #[allow(unused_variables)]
-    fn helo(&mut self, msg: &str) -> AcceptRejectAction {
+    fn helo(&mut self, ctx: &SessionContext, msg: &str) -> AcceptRejectAction {
         AcceptRejectAction::Continue
     }
 
@@ -206,18 +371,29 @@ pub trait MessageHandler {
     /// ```
     /// use rmilter::accept_reject_action::AcceptRejectAction;
     /// use rmilter::message_handler::MessageHandler;
+    /// use rmilter::session_context::SessionContext;
     ///
     /// struct MyMessageHandler {}
     ///
     /// impl MessageHandler for MyMessageHandler {
-    ///     fn mail_from(&mut self, address: &str, args: &[String]) -> AcceptRejectAction {
+    ///     fn mail_from(
+    ///         &mut self,
+    ///         ctx: &SessionContext,
+    ///         address: &str,
+    ///         args: &[String],
+    ///     ) -> AcceptRejectAction {
     ///         println!("address: {}, args: {:?}", address, args);
     ///         AcceptRejectAction::Continue
     ///     }
     /// }
     /// ```
     #[allow(unused_variables)]
-    fn mail_from(&mut self, address: &str, args: &[String]) -> AcceptRejectAction {
+    fn mail_from(
+        &mut self,
+        ctx: &SessionContext,
+        address: &str,
+        args: &[String],
+    ) -> AcceptRejectAction {
         AcceptRejectAction::Continue
     }
 
@@ -230,18 +406,81 @@ pub trait MessageHandler {
     /// ```
     /// use rmilter::accept_reject_action::AcceptRejectAction;
     /// use rmilter::message_handler::MessageHandler;
+    /// use rmilter::session_context::SessionContext;
     ///
     /// struct MyMessageHandler {}
     ///
     /// impl MessageHandler for MyMessageHandler {
-    ///     fn recipient(&mut self, recipient: &str, args: &[String]) -> AcceptRejectAction {
+    ///     fn recipient(
+    ///         &mut self,
+    ///         ctx: &SessionContext,
+    ///         recipient: &str,
+    ///         args: &[String],
+    ///     ) -> AcceptRejectAction {
     ///         println!("recipient: {}, args: {:?}", recipient, args);
     ///         AcceptRejectAction::Continue
     ///     }
     /// }
     /// ```
     #[allow(unused_variables)]
-    fn recipient(&mut self, recipient: &str, args: &[String]) -> AcceptRejectAction {
+    fn recipient(
+        &mut self,
+        ctx: &SessionContext,
+        recipient: &str,
+        args: &[String],
+    ) -> AcceptRejectAction {
+        AcceptRejectAction::Continue
+    }
+
+    /// The connection is closed because a timeout has been exceeded.
+    ///
+    /// - `kind` defines which timeout has been exceeded.
+    ///
+    /// `close` is called afterwards.
+    ///
+    /// # Example:
+    /// ```
+    /// use rmilter::message_handler::{MessageHandler, TimeoutKind};
+    /// use rmilter::session_context::SessionContext;
+    ///
+    /// struct MyMessageHandler {}
+    ///
+    /// impl MessageHandler for MyMessageHandler {
+    ///     fn timeout(&mut self, ctx: &SessionContext, kind: TimeoutKind) {
+    ///         println!("Connection timed out: {:?}", kind);
+    ///     }
+    /// }
+    /// ```
+    #[allow(unused_variables)]
+    fn timeout(&mut self, ctx: &SessionContext, kind: TimeoutKind) {}
+
+    /// An unknown or unimplemented SMTP command sent by the client (SMFIC_UNKNOWN).
+    ///
+    /// - `command` contains the complete command line including arguments.
+    ///
+    /// Can be disabled with `MilterProtocol::NO_UNKNOWN`.
+    ///
+    /// # Example:
+    /// ```
+    /// use rmilter::accept_reject_action::AcceptRejectAction;
+    /// use rmilter::message_handler::MessageHandler;
+    /// use rmilter::session_context::SessionContext;
+    ///
+    /// struct MyMessageHandler {}
+    ///
+    /// impl MessageHandler for MyMessageHandler {
+    ///     fn unknown_command(
+    ///         &mut self,
+    ///         ctx: &SessionContext,
+    ///         command: &str,
+    ///     ) -> AcceptRejectAction {
+    ///         println!("command: {}", command);
+    ///         AcceptRejectAction::Reject
+    ///     }
+    /// }
+    /// ```
+    #[allow(unused_variables)]
+    fn unknown_command(&mut self, ctx: &SessionContext, command: &str) -> AcceptRejectAction {
         AcceptRejectAction::Continue
     }
 }
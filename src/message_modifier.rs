@@ -0,0 +1,518 @@
+use std::io::Write;
+
+use crate::milter::Milter;
+use crate::milter_error::MilterError;
+use crate::milter_message::{MilterActions, ResponseMessage, MAX_BODY_CHUNK_SIZE};
+
+/// Used to modify the current message at the end of the body (SMFIC_BODYEOB).
+///
+/// Every modification is checked against the actions the MTA offered during option negotiation
+/// and is sent to the MTA right away, i.e. before the AcceptRejectAction returned by
+/// `MessageHandler::end_of_body`. It can also be used to send progress notifications to the MTA
+/// during long-running checks.
+pub struct MessageModifier<'a> {
+    actions: MilterActions,
+    stream: &'a mut (dyn Write + Send),
+}
+
+impl<'a> MessageModifier<'a> {
+    /// Returns the actions offered by the MTA during option negotiation.
+    ///
+    /// # Example:
+    /// ```
+    /// use rmilter::accept_reject_action::AcceptRejectAction;
+    /// use rmilter::message_handler::MessageHandler;
+    /// use rmilter::message_modifier::MessageModifier;
+    /// use rmilter::milter_message::MilterActions;
+    /// use rmilter::session_context::SessionContext;
+    ///
+    /// struct MyMessageHandler {}
+    ///
+    /// impl MessageHandler for MyMessageHandler {
+    ///     fn end_of_body(
+    ///         &mut self,
+    ///         ctx: &SessionContext,
+    ///         modifier: &mut MessageModifier,
+    ///     ) -> AcceptRejectAction {
+    ///         if modifier.actions().contains(MilterActions::ADD_RECIPIENTS) {
+    ///             println!("Adding recipients is possible");
+    ///         }
+    ///         AcceptRejectAction::Continue
+    ///     }
+    /// }
+    /// ```
+    pub fn actions(&self) -> MilterActions {
+        self.actions
+    }
+
+    /// Adds a header at the end of the existing headers (SMFIR_ADDHEADER).
+    ///
+    /// Requires `MilterActions::ADD_HEADERS`.
+    ///
+    /// # Example:
+    /// ```
+    /// use rmilter::accept_reject_action::AcceptRejectAction;
+    /// use rmilter::message_handler::MessageHandler;
+    /// use rmilter::message_modifier::MessageModifier;
+    /// use rmilter::session_context::SessionContext;
+    ///
+    /// struct MyMessageHandler {}
+    ///
+    /// impl MessageHandler for MyMessageHandler {
+    ///     fn end_of_body(
+    ///         &mut self,
+    ///         ctx: &SessionContext,
+    ///         modifier: &mut MessageModifier,
+    ///     ) -> AcceptRejectAction {
+    ///         match modifier.add_header("X-Spam-Status", "No") {
+    ///             Ok(_) => AcceptRejectAction::Continue,
+    ///             Err(_) => AcceptRejectAction::Tempfail,
+    ///         }
+    ///     }
+    /// }
+    /// ```
+    pub fn add_header(&mut self, name: &str, value: &str) -> Result<(), MilterError> {
+        self.check_action(MilterActions::ADD_HEADERS)?;
+        self.send(ResponseMessage::add_header(name, value)?)
+    }
+
+    /// Adds a recipient to the envelope (SMFIR_ADDRCPT).
+    ///
+    /// Requires `MilterActions::ADD_RECIPIENTS`.
+    ///
+    /// # Example:
+    /// ```
+    /// use rmilter::accept_reject_action::AcceptRejectAction;
+    /// use rmilter::message_handler::MessageHandler;
+    /// use rmilter::message_modifier::MessageModifier;
+    /// use rmilter::session_context::SessionContext;
+    ///
+    /// struct MyMessageHandler {}
+    ///
+    /// impl MessageHandler for MyMessageHandler {
+    ///     fn end_of_body(
+    ///         &mut self,
+    ///         ctx: &SessionContext,
+    ///         modifier: &mut MessageModifier,
+    ///     ) -> AcceptRejectAction {
+    ///         match modifier.add_recipient("<archive@example.com>") {
+    ///             Ok(_) => AcceptRejectAction::Continue,
+    ///             Err(_) => AcceptRejectAction::Tempfail,
+    ///         }
+    ///     }
+    /// }
+    /// ```
+    pub fn add_recipient(&mut self, recipient: &str) -> Result<(), MilterError> {
+        self.check_action(MilterActions::ADD_RECIPIENTS)?;
+        self.send(ResponseMessage::add_recipient(recipient)?)
+    }
+
+    /// Adds a recipient including ESMTP arguments to the envelope (SMFIR_ADDRCPT_PAR).
+    ///
+    /// - `args` contains the ESMTP arguments separated by spaces (e.g. `NOTIFY=NEVER`).
+    ///
+    /// Requires `MilterActions::ADD_RECIPIENTS_WITH_ARGS`.
+    ///
+    /// # Example:
+    /// ```
+    /// use rmilter::accept_reject_action::AcceptRejectAction;
+    /// use rmilter::message_handler::MessageHandler;
+    /// use rmilter::message_modifier::MessageModifier;
+    /// use rmilter::session_context::SessionContext;
+    ///
+    /// struct MyMessageHandler {}
+    ///
+    /// impl MessageHandler for MyMessageHandler {
+    ///     fn end_of_body(
+    ///         &mut self,
+    ///         ctx: &SessionContext,
+    ///         modifier: &mut MessageModifier,
+    ///     ) -> AcceptRejectAction {
+    ///         match modifier.add_recipient_with_args("<archive@example.com>", "NOTIFY=NEVER") {
+    ///             Ok(_) => AcceptRejectAction::Continue,
+    ///             Err(_) => AcceptRejectAction::Tempfail,
+    ///         }
+    ///     }
+    /// }
+    /// ```
+    pub fn add_recipient_with_args(
+        &mut self,
+        recipient: &str,
+        args: &str,
+    ) -> Result<(), MilterError> {
+        self.check_action(MilterActions::ADD_RECIPIENTS_WITH_ARGS)?;
+        self.send(ResponseMessage::add_recipient_with_args(recipient, args)?)
+    }
+
+    /// Changes the envelope sender (SMFIR_CHGFROM).
+    ///
+    /// - `sender` contains the new address of the sender.
+    /// - `args` contains optional ESMTP arguments separated by spaces (e.g. `SIZE=1234`).
+    ///
+    /// Requires `MilterActions::CHANGE_FROM`.
+    ///
+    /// # Example:
+    /// ```
+    /// use rmilter::accept_reject_action::AcceptRejectAction;
+    /// use rmilter::message_handler::MessageHandler;
+    /// use rmilter::message_modifier::MessageModifier;
+    /// use rmilter::session_context::SessionContext;
+    ///
+    /// struct MyMessageHandler {}
+    ///
+    /// impl MessageHandler for MyMessageHandler {
+    ///     fn end_of_body(
+    ///         &mut self,
+    ///         ctx: &SessionContext,
+    ///         modifier: &mut MessageModifier,
+    ///     ) -> AcceptRejectAction {
+    ///         match modifier.change_from("<SRS0=HHH=TT=example.org=user@example.com>", None) {
+    ///             Ok(_) => AcceptRejectAction::Continue,
+    ///             Err(_) => AcceptRejectAction::Tempfail,
+    ///         }
+    ///     }
+    /// }
+    /// ```
+    pub fn change_from(&mut self, sender: &str, args: Option<&str>) -> Result<(), MilterError> {
+        self.check_action(MilterActions::CHANGE_FROM)?;
+        self.send(ResponseMessage::change_from(sender, args)?)
+    }
+
+    /// Changes the value of the `index`-th occurrence (starting at 1) of the header `name`
+    /// (SMFIR_CHGHEADER).
+    ///
+    /// Requires `MilterActions::CHANGE_HEADERS`.
+    ///
+    /// # Example:
+    /// ```
+    /// use rmilter::accept_reject_action::AcceptRejectAction;
+    /// use rmilter::message_handler::MessageHandler;
+    /// use rmilter::message_modifier::MessageModifier;
+    /// use rmilter::session_context::SessionContext;
+    ///
+    /// struct MyMessageHandler {}
+    ///
+    /// impl MessageHandler for MyMessageHandler {
+    ///     fn end_of_body(
+    ///         &mut self,
+    ///         ctx: &SessionContext,
+    ///         modifier: &mut MessageModifier,
+    ///     ) -> AcceptRejectAction {
+    ///         match modifier.change_header("Subject", 1, "[SPAM] Hello") {
+    ///             Ok(_) => AcceptRejectAction::Continue,
+    ///             Err(_) => AcceptRejectAction::Tempfail,
+    ///         }
+    ///     }
+    /// }
+    /// ```
+    pub fn change_header(
+        &mut self,
+        name: &str,
+        index: u32,
+        value: &str,
+    ) -> Result<(), MilterError> {
+        self.check_action(MilterActions::CHANGE_HEADERS)?;
+        self.send(ResponseMessage::change_header(name, index, value)?)
+    }
+
+    fn check_action(&self, action: MilterActions) -> Result<(), MilterError> {
+        if self.actions.contains(action) {
+            Ok(())
+        } else {
+            Err(MilterError::ActionNotNegotiated(action))
+        }
+    }
+
+    /// Deletes the `index`-th occurrence (starting at 1) of the header `name` (SMFIR_CHGHEADER
+    /// with an empty value).
+    ///
+    /// Requires `MilterActions::CHANGE_HEADERS`.
+    ///
+    /// # Example:
+    /// ```
+    /// use rmilter::accept_reject_action::AcceptRejectAction;
+    /// use rmilter::message_handler::MessageHandler;
+    /// use rmilter::message_modifier::MessageModifier;
+    /// use rmilter::session_context::SessionContext;
+    ///
+    /// struct MyMessageHandler {}
+    ///
+    /// impl MessageHandler for MyMessageHandler {
+    ///     fn end_of_body(
+    ///         &mut self,
+    ///         ctx: &SessionContext,
+    ///         modifier: &mut MessageModifier,
+    ///     ) -> AcceptRejectAction {
+    ///         match modifier.delete_header("X-Spam-Status", 1) {
+    ///             Ok(_) => AcceptRejectAction::Continue,
+    ///             Err(_) => AcceptRejectAction::Tempfail,
+    ///         }
+    ///     }
+    /// }
+    /// ```
+    pub fn delete_header(&mut self, name: &str, index: u32) -> Result<(), MilterError> {
+        self.change_header(name, index, "")
+    }
+
+    /// Removes a recipient from the envelope (SMFIR_DELRCPT).
+    ///
+    /// Requires `MilterActions::REMOVE_RECIPIENTS`.
+    ///
+    /// # Example:
+    /// ```
+    /// use rmilter::accept_reject_action::AcceptRejectAction;
+    /// use rmilter::message_handler::MessageHandler;
+    /// use rmilter::message_modifier::MessageModifier;
+    /// use rmilter::session_context::SessionContext;
+    ///
+    /// struct MyMessageHandler {}
+    ///
+    /// impl MessageHandler for MyMessageHandler {
+    ///     fn end_of_body(
+    ///         &mut self,
+    ///         ctx: &SessionContext,
+    ///         modifier: &mut MessageModifier,
+    ///     ) -> AcceptRejectAction {
+    ///         match modifier.delete_recipient("<user@example.com>") {
+    ///             Ok(_) => AcceptRejectAction::Continue,
+    ///             Err(_) => AcceptRejectAction::Tempfail,
+    ///         }
+    ///     }
+    /// }
+    /// ```
+    pub fn delete_recipient(&mut self, recipient: &str) -> Result<(), MilterError> {
+        self.check_action(MilterActions::REMOVE_RECIPIENTS)?;
+        self.send(ResponseMessage::delete_recipient(recipient)?)
+    }
+
+    /// Inserts a header at position `index` (starting at 0) of the existing headers
+    /// (SMFIR_INSHEADER).
+    ///
+    /// Requires `MilterActions::ADD_HEADERS`.
+    ///
+    /// # Example:
+    /// ```
+    /// use rmilter::accept_reject_action::AcceptRejectAction;
+    /// use rmilter::message_handler::MessageHandler;
+    /// use rmilter::message_modifier::MessageModifier;
+    /// use rmilter::session_context::SessionContext;
+    ///
+    /// struct MyMessageHandler {}
+    ///
+    /// impl MessageHandler for MyMessageHandler {
+    ///     fn end_of_body(
+    ///         &mut self,
+    ///         ctx: &SessionContext,
+    ///         modifier: &mut MessageModifier,
+    ///     ) -> AcceptRejectAction {
+    ///         match modifier.insert_header("X-Spam-Status", 0, "No") {
+    ///             Ok(_) => AcceptRejectAction::Continue,
+    ///             Err(_) => AcceptRejectAction::Tempfail,
+    ///         }
+    ///     }
+    /// }
+    /// ```
+    pub fn insert_header(
+        &mut self,
+        name: &str,
+        index: u32,
+        value: &str,
+    ) -> Result<(), MilterError> {
+        self.check_action(MilterActions::ADD_HEADERS)?;
+        self.send(ResponseMessage::insert_header(name, index, value)?)
+    }
+
+    pub(crate) fn new(stream: &'a mut (dyn Write + Send), actions: MilterActions) -> Self {
+        Self { actions, stream }
+    }
+
+    /// Informs the MTA that the milter is still working on the message (SMFIR_PROGRESS).
+    ///
+    /// Call this method regularly during long-running checks to prevent the MTA from running into
+    /// its milter timeout. No action has to be negotiated for this.
+    ///
+    /// # Example:
+    /// ```
+    /// use rmilter::accept_reject_action::AcceptRejectAction;
+    /// use rmilter::message_handler::MessageHandler;
+    /// use rmilter::message_modifier::MessageModifier;
+    /// use rmilter::session_context::SessionContext;
+    ///
+    /// struct MyMessageHandler {}
+    ///
+    /// impl MessageHandler for MyMessageHandler {
+    ///     fn end_of_body(
+    ///         &mut self,
+    ///         ctx: &SessionContext,
+    ///         modifier: &mut MessageModifier,
+    ///     ) -> AcceptRejectAction {
+    ///         for _ in 0..3 {
+    ///             // Do some long-running work here
+    ///             if modifier.progress().is_err() {
+    ///                 return AcceptRejectAction::Tempfail;
+    ///             }
+    ///         }
+    ///         AcceptRejectAction::Continue
+    ///     }
+    /// }
+    /// ```
+    pub fn progress(&mut self) -> Result<(), MilterError> {
+        self.send(ResponseMessage::progress()?)
+    }
+
+    /// Quarantines the message, i.e. puts it into the hold queue of the MTA (SMFIR_QUARANTINE).
+    ///
+    /// - `reason` describes why the message was quarantined.
+    ///
+    /// Requires `MilterActions::QUARANTINE`.
+    ///
+    /// # Example:
+    /// ```
+    /// use rmilter::accept_reject_action::AcceptRejectAction;
+    /// use rmilter::message_handler::MessageHandler;
+    /// use rmilter::message_modifier::MessageModifier;
+    /// use rmilter::session_context::SessionContext;
+    ///
+    /// struct MyMessageHandler {}
+    ///
+    /// impl MessageHandler for MyMessageHandler {
+    ///     fn end_of_body(
+    ///         &mut self,
+    ///         ctx: &SessionContext,
+    ///         modifier: &mut MessageModifier,
+    ///     ) -> AcceptRejectAction {
+    ///         match modifier.quarantine("Suspicious attachment") {
+    ///             Ok(_) => AcceptRejectAction::Continue,
+    ///             Err(_) => AcceptRejectAction::Tempfail,
+    ///         }
+    ///     }
+    /// }
+    /// ```
+    pub fn quarantine(&mut self, reason: &str) -> Result<(), MilterError> {
+        self.check_action(MilterActions::QUARANTINE)?;
+        self.send(ResponseMessage::quarantine(reason)?)
+    }
+
+    /// Replaces the body of the message (SMFIR_REPLBODY).
+    ///
+    /// Large bodies are automatically split into multiple chunks. Calling this method more than
+    /// once appends `body` to the replacement body sent before.
+    ///
+    /// Requires `MilterActions::CHANGE_BODY`.
+    ///
+    /// # Example:
+    /// ```
+    /// use rmilter::accept_reject_action::AcceptRejectAction;
+    /// use rmilter::message_handler::MessageHandler;
+    /// use rmilter::message_modifier::MessageModifier;
+    /// use rmilter::session_context::SessionContext;
+    ///
+    /// struct MyMessageHandler {
+    ///     body: Vec<u8>,
+    /// }
+    ///
+    /// impl MessageHandler for MyMessageHandler {
+    ///     fn end_of_body(
+    ///         &mut self,
+    ///         ctx: &SessionContext,
+    ///         modifier: &mut MessageModifier,
+    ///     ) -> AcceptRejectAction {
+    ///         self.body.extend_from_slice(b"\r\n-- \r\nThis mail was scanned.\r\n");
+    ///
+    ///         match modifier.replace_body(&self.body) {
+    ///             Ok(_) => AcceptRejectAction::Continue,
+    ///             Err(_) => AcceptRejectAction::Tempfail,
+    ///         }
+    ///     }
+    /// }
+    /// ```
+    pub fn replace_body(&mut self, body: &[u8]) -> Result<(), MilterError> {
+        self.check_action(MilterActions::CHANGE_BODY)?;
+
+        if body.is_empty() {
+            return self.send(ResponseMessage::replace_body(body)?);
+        }
+
+        for chunk in body.chunks(MAX_BODY_CHUNK_SIZE) {
+            self.send(ResponseMessage::replace_body(chunk)?)?;
+        }
+
+        Ok(())
+    }
+
+    fn send(&mut self, response_msg: ResponseMessage) -> Result<(), MilterError> {
+        Milter::send_response(self.stream, response_msg)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn add_header_not_negotiated() {
+        let mut buf = Vec::new();
+        let mut modifier = MessageModifier::new(&mut buf, MilterActions::CHANGE_HEADERS);
+
+        assert!(matches!(
+            modifier.add_header("X-Spam-Status", "No"),
+            Err(MilterError::ActionNotNegotiated(MilterActions::ADD_HEADERS))
+        ));
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn add_recipient_with_args_not_negotiated() {
+        let mut buf = Vec::new();
+        let mut modifier = MessageModifier::new(&mut buf, MilterActions::ADD_RECIPIENTS);
+
+        assert!(matches!(
+            modifier.add_recipient_with_args("<a@b.c>", "NOTIFY=NEVER"),
+            Err(MilterError::ActionNotNegotiated(
+                MilterActions::ADD_RECIPIENTS_WITH_ARGS
+            ))
+        ));
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn delete_header_sends_empty_value() {
+        let mut buf = Vec::new();
+        let mut modifier = MessageModifier::new(&mut buf, MilterActions::CHANGE_HEADERS);
+
+        modifier.delete_header("X-A", 1).unwrap();
+
+        assert_eq!(
+            &b"\x00\x00\x00\x0am\x00\x00\x00\x01X-A\x00\x00"[..],
+            &buf[..]
+        );
+    }
+
+    #[test]
+    fn replace_body_is_chunked() {
+        let body = vec![b'x'; MAX_BODY_CHUNK_SIZE + 10];
+        let mut buf = Vec::new();
+        let mut modifier = MessageModifier::new(&mut buf, MilterActions::CHANGE_BODY);
+
+        modifier.replace_body(&body).unwrap();
+
+        assert_eq!(body.len() + 2 * 5, buf.len());
+        assert_eq!(&[0, 1, 0, 0, b'b'], &buf[..5]);
+        assert_eq!(
+            &[0, 0, 0, 11, b'b'],
+            &buf[MAX_BODY_CHUNK_SIZE + 5..MAX_BODY_CHUNK_SIZE + 10]
+        );
+    }
+
+    #[test]
+    fn replace_body_not_negotiated() {
+        let mut buf = Vec::new();
+        let mut modifier = MessageModifier::new(&mut buf, MilterActions::ADD_HEADERS);
+
+        assert!(matches!(
+            modifier.replace_body(b"body"),
+            Err(MilterError::ActionNotNegotiated(MilterActions::CHANGE_BODY))
+        ));
+        assert!(buf.is_empty());
+    }
+}
@@ -1,186 +1,555 @@
-use std::convert::{TryFrom, TryInto};
-use std::io::{Read, Write};
-use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+use std::io::{ErrorKind, Write};
+use std::net::{TcpListener, ToSocketAddrs};
+#[cfg(unix)]
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+#[cfg(unix)]
+use std::os::unix::net::{UnixListener, UnixStream};
+#[cfg(unix)]
+use std::path::Path;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
 
-use crate::message_handler::MessageHandler;
+use crate::message_handler::{MessageHandler, TimeoutKind};
 use crate::milter_error::MilterError;
-use crate::milter_message::{MilterMessage, MilterProtocol, ResponseMessage};
+use crate::milter_message::{
+    MacroStage, MilterActions, MilterProtocol, NegotiatedOptions, ResponseMessage,
+};
+use crate::milter_socket::MilterSocket;
+use crate::session::{ActiveSessions, Session, SessionStream};
+use crate::shutdown_handle::{ListenerAddress, ShutdownHandle, DEFAULT_SHUTDOWN_TIMEOUT};
+#[cfg(unix)]
+use crate::systemd::{self, ActivatedListener};
+
+/// The configuration of a Milter defined using the `MilterBuilder`.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct MilterConfig {
+    pub actions: Option<MilterActions>,
+    pub idle_timeout: Option<Duration>,
+    pub macros: Vec<(MacroStage, Vec<String>)>,
+    pub max_sessions: Option<usize>,
+    pub protocol: Option<MilterProtocol>,
+    pub read_timeout: Option<Duration>,
+    pub shutdown_timeout: Option<Duration>,
+    pub socket: Option<MilterSocket>,
+    pub socket_owner: (Option<u32>, Option<u32>),
+    pub socket_permissions: Option<u32>,
+    pub write_timeout: Option<Duration>,
+}
+
+impl MilterConfig {
+    /// Adds `macros` to the macros requested for `stage`.
+    pub(crate) fn add_macros(&mut self, stage: MacroStage, macros: &[&str]) {
+        let macros = macros.iter().map(|m| String::from(*m));
+
+        match self.macros.iter_mut().find(|(s, _)| *s == stage) {
+            Some((_, list)) => list.extend(macros),
+            None => self.macros.push((stage, macros.collect())),
+        }
+    }
+
+    /// Negotiates the options offered by the MTA with this configuration.
+    pub(crate) fn negotiate(
+        &self,
+        version: u32,
+        actions: MilterActions,
+        protocol: MilterProtocol,
+    ) -> Result<NegotiatedOptions, MilterError> {
+        NegotiatedOptions::negotiate(
+            version,
+            actions,
+            protocol,
+            self.actions,
+            self.protocol.unwrap_or_default(),
+            &self.macros,
+        )
+    }
+
+    /// Returns the configured timeout of the given kind.
+    pub(crate) fn timeout(&self, kind: TimeoutKind) -> Option<Duration> {
+        match kind {
+            TimeoutKind::Idle => self.idle_timeout,
+            TimeoutKind::Read => self.read_timeout,
+            TimeoutKind::Write => self.write_timeout,
+        }
+    }
+
+    /// Applies the configured permissions and owner to the socket file at `path`.
+    #[cfg(unix)]
+    pub(crate) fn prepare_socket_file(&self, path: &Path) -> Result<(), MilterError> {
+        if let Some(mode) = self.socket_permissions {
+            std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode))?;
+        }
+
+        if let (None, None) = self.socket_owner {
+            return Ok(());
+        }
+
+        let (uid, gid) = self.socket_owner;
+        std::os::unix::fs::chown(path, uid, gid)?;
+
+        Ok(())
+    }
+}
+
+/// Creates a new `MessageHandler` for each connection.
+pub(crate) type MessageHandlerFactory = dyn Fn() -> Box<dyn MessageHandler + Send> + Send + Sync;
+
+/// Defines where the `MessageHandler` of a connection comes from.
+pub(crate) enum MessageHandlerSource<'a> {
+    /// A single `MessageHandler` used for all connections, one connection at a time
+    Borrowed(&'a mut dyn MessageHandler),
+    /// A new `MessageHandler` for each connection, handled on its own thread
+    Factory(Arc<MessageHandlerFactory>),
+}
 
 /// This is the main struct that opens the milter connection.
 ///
-/// Also holds the `MessageHandler`.
+/// Also holds the `MessageHandler` (or the factory creating a `MessageHandler` per connection).
 pub struct Milter<'a> {
-    message_handler: &'a mut dyn MessageHandler,
-    protocol: Option<MilterProtocol>,
+    active_sessions: ActiveSessions,
+    config: Arc<MilterConfig>,
+    message_handler: MessageHandlerSource<'a>,
+    shutdown: ShutdownHandle,
 }
 
 impl<'a> Milter<'a> {
-    fn handle_message(&mut self, s: &mut TcpStream, buffer: &[u8]) -> Result<bool, MilterError> {
-        let mut keep_open = true;
-
-        match MilterMessage::try_from(buffer) {
-            Ok(message) => {
-                match message {
-                    MilterMessage::AbortFilterChecks => self.message_handler.abort_filter_checks(),
-                    MilterMessage::BodyChunk { value } => {
-                        let action = self.message_handler.body_chunk(&value);
-                        self.send_response(s, action)?;
-                    }
-                    MilterMessage::ConnectionInformation {
-                        hostname,
-                        family,
-                        port,
-                        address,
-                    } => {
-                        let action = self
-                            .message_handler
-                            .connection(&hostname, &family, &port, &address);
-                        self.send_response(s, action)?;
-                    }
-                    MilterMessage::DefineMacros { cmdcode, macros } => {
-                        self.message_handler.define_macros(&cmdcode, macros);
-                    }
-                    MilterMessage::EndOfBody => {
-                        let action = self.message_handler.end_of_body();
-                        self.send_response(s, action)?;
-                    }
-                    MilterMessage::EndOfHeader => {
-                        let action = self.message_handler.end_of_header();
-                        self.send_response(s, action)?;
-                    }
-                    MilterMessage::Header { name, value } => {
-                        let action = self.message_handler.header(&name, &value);
-                        self.send_response(s, action)?;
-                    }
-                    MilterMessage::Helo { msg } => {
-                        let action = self.message_handler.helo(&msg);
-                        self.send_response(s, action)?;
-                    }
-                    MilterMessage::MailFrom { sender, args } => {
-                        let action = self.message_handler.mail_from(&sender, &args);
-                        self.send_response(s, action)?;
-                    }
-                    MilterMessage::OptionNegotiation {
-                        version,
-                        actions,
-                        protocol: _,
-                    } => {
-                        let response_msg = ResponseMessage::option_negotiation(
-                            version,
-                            actions,
-                            self.protocol.as_ref().unwrap_or(&MilterProtocol::default()),
-                        );
-
-                        self.send_response(s, response_msg)?;
-                    }
-                    MilterMessage::QuitCommunication => {
-                        keep_open = false;
-                    }
-                    MilterMessage::RecipientInformation { recipient, args } => {
-                        let action = self.message_handler.recipient(&recipient, &args);
-                        self.send_response(s, action)?;
-                    }
-                };
+    /// Handles the connections returned by `accept` until a shutdown is requested.
+    fn accept_connections<S, A>(&mut self, mut accept: A) -> Result<(), MilterError>
+    where
+        S: SessionStream,
+        A: FnMut() -> std::io::Result<S>,
+    {
+        while !self.shutdown.is_shutdown() {
+            match accept() {
+                // The connection may be the one waking up the accept after a shutdown request
+                Ok(_) if self.shutdown.is_shutdown() => break,
+                Ok(stream) => self.handle_stream(stream)?,
+                Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(e) => return Err(e.into()),
             }
-            Err(_e) => {
-                let mut response = Vec::with_capacity(5);
-                response.append(&mut u32::to_be_bytes(1).to_vec());
-                response.push(b'c');
+        }
 
-                s.write_all(&response)?;
+        Ok(())
+    }
+
+    /// Handles a connection of the MTA, either directly or on a new worker thread.
+    fn handle_stream<S: SessionStream>(&mut self, stream: S) -> Result<(), MilterError> {
+        let registration = self.shutdown.register(Box::new(stream.try_clone_stream()?));
+
+        match &mut self.message_handler {
+            MessageHandlerSource::Borrowed(message_handler) => {
+                let _registration = registration;
+                Session::new(&mut **message_handler, &self.config).handle_stream(stream)
             }
-        }
+            MessageHandlerSource::Factory(factory) => {
+                let factory = Arc::clone(factory);
+                let config = Arc::clone(&self.config);
+                let guard = match self
+                    .active_sessions
+                    .acquire(config.max_sessions, &self.shutdown)
+                {
+                    Some(guard) => guard,
+                    // The connection is dropped, as the milter is shutting down
+                    None => return Ok(()),
+                };
 
-        Ok(keep_open)
-    }
-
-    fn handle_stream(&mut self, mut stream: TcpStream) -> Result<(), MilterError> {
-        let u32_size = std::mem::size_of::<u32>();
-        let mut buffer = [0; 128];
-        let mut collected_bytes = Vec::new();
-
-        loop {
-            let mut keep_open = true;
-
-            match stream.read(&mut buffer) {
-                Ok(0) => {
-                    println!("Closing connection");
-                    break;
-                }
-                Ok(len) => {
-                    // First, add everything read to collected_bytes
-                    collected_bytes.extend_from_slice(&buffer[..len]);
-
-                    if collected_bytes.len() >= u32_size {
-                        let mut msg_len: usize =
-                            u32::from_be_bytes(collected_bytes[..u32_size].try_into()?)
-                                .try_into()?;
-
-                        while collected_bytes.len() >= u32_size + msg_len {
-                            // Only remove first 4 bytes when the complete message is available
-                            collected_bytes.drain(..u32_size);
-                            let msg: Vec<u8> = collected_bytes.drain(..msg_len).collect();
-
-                            if !self.handle_message(&mut stream, &msg)? {
-                                keep_open = false;
-                                break;
-                            }
-
-                            if collected_bytes.len() >= std::mem::size_of::<u32>() {
-                                msg_len =
-                                    u32::from_be_bytes(collected_bytes[..u32_size].try_into()?)
-                                        .try_into()?;
-                            }
+                thread::Builder::new()
+                    .name("rmilter-session".into())
+                    .spawn(move || {
+                        let _guard = guard;
+                        let _registration = registration;
+                        let mut message_handler = factory();
+
+                        if let Err(e) =
+                            Session::new(&mut *message_handler, &config).handle_stream(stream)
+                        {
+                            eprintln!("Error while handling connection: {}", e);
                         }
-                    }
-                }
-                Err(e) => {
-                    eprintln!("Error while receiving data: {}", e);
-                    break;
-                }
-            }
+                    })?;
 
-            if !keep_open {
-                break;
+                Ok(())
             }
         }
-        Ok(())
     }
 
-    pub(crate) fn new(
-        message_handler: &'a mut dyn MessageHandler,
-        protocol: Option<MilterProtocol>,
-    ) -> Self {
+    pub(crate) fn new(message_handler: MessageHandlerSource<'a>, config: MilterConfig) -> Self {
+        let active_sessions = ActiveSessions::default();
+
         Self {
+            shutdown: ShutdownHandle::new(
+                config.shutdown_timeout.unwrap_or(DEFAULT_SHUTDOWN_TIMEOUT),
+                active_sessions.clone(),
+            ),
+            active_sessions,
+            config: Arc::new(config),
             message_handler,
-            protocol,
         }
     }
 
     /// Opens the connection to the MTA service.
     ///
     /// - `address` defines the socket address of the MTA.
+    ///
+    /// Runs until a shutdown is requested using the `ShutdownHandle` (see `Milter::shutdown_handle`).
     pub fn run<S: ToSocketAddrs>(&'a mut self, address: S) -> Result<(), MilterError> {
         let listener = TcpListener::bind(address)?;
+        let address = ListenerAddress::tcp(listener.local_addr()?);
 
-        for stream in listener.incoming() {
-            self.handle_stream(stream?)?;
+        self.serve(Some(address), || {
+            listener.accept().map(|(stream, _)| stream)
+        })
+    }
+
+    /// Opens the connection to the MTA service using the socket defined with
+    /// `MilterBuilder::set_socket`.
+    ///
+    /// # Example
+    /// ```no_run
+    /// use rmilter::milter_builder::MilterBuilder;
+    /// use rmilter::message_handler::MessageHandler;
+    ///
+    /// struct MyHandler;
+    /// impl MessageHandler for MyHandler {}
+    ///
+    /// let mut handler = MyHandler {};
+    ///
+    /// let mut milter = MilterBuilder::new(&mut handler)
+    ///     .set_socket("inet:8890@localhost".parse().expect("Invalid socket"))
+    ///     .build();
+    ///
+    /// milter.start().expect("Failed to start milter");
+    /// ```
+    pub fn start(&'a mut self) -> Result<(), MilterError> {
+        match self.config.socket.clone() {
+            Some(MilterSocket::Unix(path)) => self.start_unix(path),
+            Some(socket) => {
+                let addrs = socket.socket_addrs()?;
+                self.run(&addrs[..])
+            }
+            None => Err(MilterError::InvalidSocket("no socket defined".into())),
         }
+    }
 
-        Ok(())
+    /// Opens the connection to the MTA service using a listening socket passed by systemd
+    /// (socket activation).
+    ///
+    /// - `name` selects the socket by its `FileDescriptorName=` in the socket unit. Without a
+    ///   name, exactly one socket must have been passed.
+    ///
+    /// TCP and unix domain sockets are supported. The socket file of a unix domain socket is
+    /// managed by systemd and therefore not removed.
+    ///
+    /// # Example
+    /// ```no_run
+    /// use rmilter::milter_builder::MilterBuilder;
+    /// use rmilter::message_handler::MessageHandler;
+    ///
+    /// struct MyHandler;
+    /// impl MessageHandler for MyHandler {}
+    ///
+    /// let mut milter = MilterBuilder::from_factory(|| MyHandler {}).build();
+    ///
+    /// milter
+    ///     .run_systemd(Some("milter"))
+    ///     .expect("Failed to start milter");
+    /// ```
+    #[cfg(unix)]
+    pub fn run_systemd(&'a mut self, name: Option<&str>) -> Result<(), MilterError> {
+        match systemd::take_listener(name)? {
+            // The socket may have been passed in non-blocking mode (`NonBlocking=` in the
+            // socket unit)
+            ActivatedListener::Tcp(listener) => {
+                listener.set_nonblocking(false)?;
+                let address = ListenerAddress::tcp(listener.local_addr()?);
+
+                self.serve(Some(address), || {
+                    listener.accept().map(|(stream, _)| stream)
+                })
+            }
+            ActivatedListener::Unix(listener) => {
+                listener.set_nonblocking(false)?;
+                let address = unix_listener_address(&listener)?;
+
+                self.serve(address, || listener.accept().map(|(stream, _)| stream))
+            }
+        }
     }
 
-    fn send_response<R: Into<ResponseMessage>>(
-        &self,
-        s: &mut TcpStream,
+    /// Returns a `ShutdownHandle` that can be used to stop the milter from another thread.
+    ///
+    /// # Example
+    /// ```
+    /// use rmilter::milter_builder::MilterBuilder;
+    /// use rmilter::message_handler::MessageHandler;
+    ///
+    /// struct MyHandler;
+    /// impl MessageHandler for MyHandler {}
+    ///
+    /// let milter = MilterBuilder::from_factory(|| MyHandler {}).build();
+    /// let shutdown_handle = milter.shutdown_handle();
+    ///
+    /// std::thread::spawn(move || shutdown_handle.shutdown());
+    /// ```
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        self.shutdown.clone()
+    }
+
+    #[cfg(unix)]
+    fn start_unix(&'a mut self, path: std::path::PathBuf) -> Result<(), MilterError> {
+        self.run_unix(path)
+    }
+
+    #[cfg(not(unix))]
+    fn start_unix(&'a mut self, path: std::path::PathBuf) -> Result<(), MilterError> {
+        Err(MilterError::InvalidSocket(format!(
+            "unix domain sockets are not supported on this platform: {}",
+            path.display()
+        )))
+    }
+
+    /// Opens the connection to the MTA service using a unix domain socket.
+    ///
+    /// - `path` defines the path of the socket.
+    ///
+    /// A stale socket file left behind at `path` (e.g. after a crash) is removed before binding.
+    /// If another process is still listening on the socket, an error is returned. The
+    /// permissions and the owner of the socket file can be defined using the `MilterBuilder`.
+    /// The socket file is removed when the milter stops.
+    ///
+    /// # Example
+    /// ```no_run
+    /// use rmilter::milter_builder::MilterBuilder;
+    /// use rmilter::message_handler::MessageHandler;
+    ///
+    /// struct MyHandler;
+    /// impl MessageHandler for MyHandler {}
+    ///
+    /// let mut handler = MyHandler {};
+    ///
+    /// let mut milter = MilterBuilder::new(&mut handler)
+    ///     .set_socket_permissions(0o660)
+    ///     .build();
+    ///
+    /// milter
+    ///     .run_unix("/var/spool/postfix/milter/rmilter.sock")
+    ///     .expect("Failed to start milter");
+    /// ```
+    #[cfg(unix)]
+    pub fn run_unix<P: AsRef<Path>>(&'a mut self, path: P) -> Result<(), MilterError> {
+        let path = path.as_ref();
+
+        remove_stale_socket(path)?;
+        let listener = UnixListener::bind(path)?;
+        let _socket_file = SocketFileGuard(path);
+
+        self.config
+            .prepare_socket_file(path)
+            .and_then(|_| unix_listener_address(&listener))
+            .and_then(|address| self.serve(address, || listener.accept().map(|(stream, _)| stream)))
+    }
+
+    /// Handles the connections returned by `accept` until a shutdown is requested.
+    ///
+    /// `listener` is the address `accept` listens on, which is connected to by
+    /// `ShutdownHandle::shutdown` to end a blocking `accept`. If the milter runs as a systemd
+    /// service, systemd is notified about the startup and the shutdown.
+    fn serve<S, A>(
+        &mut self,
+        listener: Option<ListenerAddress>,
+        accept: A,
+    ) -> Result<(), MilterError>
+    where
+        S: SessionStream,
+        A: FnMut() -> std::io::Result<S>,
+    {
+        // The listener is set before checking for a shutdown, so a shutdown requested in between
+        // wakes up the accept
+        self.shutdown.set_listener(listener);
+        notify_systemd("READY=1");
+
+        let result = self.accept_connections(accept);
+
+        if result.is_ok() {
+            notify_systemd("STOPPING=1");
+
+            if !self
+                .active_sessions
+                .wait_idle(Instant::now() + self.shutdown.timeout())
+            {
+                eprintln!("Closing connections still active after the shutdown timeout");
+                self.shutdown.close_streams();
+            }
+        }
+
+        self.shutdown.stopped();
+
+        result
+    }
+
+    pub(crate) fn send_response<R: Into<ResponseMessage>>(
+        s: &mut (dyn Write + Send),
         response_msg: R,
     ) -> Result<(), MilterError> {
         let response_msg = response_msg.into();
         let response = response_msg.get_content();
 
-        s.write_all(&response)?;
+        s.write_all(response)?;
         s.flush()?;
 
         Ok(())
     }
 }
+
+/// Sends a state change to systemd, if the milter runs as a systemd service.
+#[cfg(unix)]
+fn notify_systemd(state: &str) {
+    if let Err(e) = systemd::notify(state) {
+        eprintln!("Failed to notify systemd: {}", e);
+    }
+}
+
+#[cfg(not(unix))]
+fn notify_systemd(_state: &str) {}
+
+/// Removes the socket file at `path` if no process is listening on it anymore, i.e. connecting
+/// to it is refused.
+#[cfg(unix)]
+pub(crate) fn remove_stale_socket(path: &Path) -> Result<(), MilterError> {
+    match std::fs::symlink_metadata(path) {
+        Ok(metadata) if !metadata.file_type().is_socket() => Err(std::io::Error::new(
+            std::io::ErrorKind::AlreadyExists,
+            format!("{} exists and is not a socket", path.display()),
+        )
+        .into()),
+        Ok(_) => match UnixStream::connect(path) {
+            Ok(_) => Err(std::io::Error::new(
+                std::io::ErrorKind::AddrInUse,
+                format!("{} is in use by another process", path.display()),
+            )
+            .into()),
+            Err(e) if e.kind() == ErrorKind::ConnectionRefused => Ok(std::fs::remove_file(path)?),
+            Err(e) => Err(e.into()),
+        },
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Removes the socket file at the given path when dropped, so the file is also removed if a
+/// running milter panics or its future is dropped.
+#[cfg(unix)]
+pub(crate) struct SocketFileGuard<'a>(pub(crate) &'a Path);
+
+#[cfg(unix)]
+impl Drop for SocketFileGuard<'_> {
+    fn drop(&mut self) {
+        if let Err(e) = std::fs::remove_file(self.0) {
+            eprintln!("Failed to remove socket file {}: {}", self.0.display(), e);
+        }
+    }
+}
+
+/// Returns the address of a unix domain socket listener, unless it has no path (e.g. abstract
+/// sockets).
+#[cfg(unix)]
+fn unix_listener_address(listener: &UnixListener) -> Result<Option<ListenerAddress>, MilterError> {
+    Ok(listener
+        .local_addr()?
+        .as_pathname()
+        .map(|path| ListenerAddress::Unix(path.to_path_buf())))
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use crate::milter_builder::MilterBuilder;
+
+    struct MyMessageHandler {}
+
+    impl MessageHandler for MyMessageHandler {}
+
+    fn socket_path(name: &str) -> std::path::PathBuf {
+        std::env::temp_dir().join(format!("rmilter-{}-{}.sock", name, std::process::id()))
+    }
+
+    #[test]
+    fn prepare_socket_file_sets_permissions_and_owner() {
+        use std::os::unix::fs::MetadataExt;
+
+        let dir = std::env::temp_dir().join(format!("rmilter-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("prepare.sock");
+        let _listener = UnixListener::bind(&path).unwrap();
+
+        let metadata = std::fs::metadata(&path).unwrap();
+        let config = MilterConfig {
+            socket_owner: (Some(metadata.uid()), Some(metadata.gid())),
+            socket_permissions: Some(0o640),
+            ..MilterConfig::default()
+        };
+
+        config.prepare_socket_file(&path).unwrap();
+
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        assert!(std::fs::metadata(&path).unwrap().file_type().is_socket());
+        assert_eq!(0o640, mode & 0o777);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn remove_stale_socket_removes_unused_socket() {
+        let path = socket_path("stale");
+        drop(UnixListener::bind(&path).unwrap());
+
+        remove_stale_socket(&path).unwrap();
+
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn remove_stale_socket_keeps_socket_in_use() {
+        let path = socket_path("in-use");
+        let _listener = UnixListener::bind(&path).unwrap();
+
+        assert!(remove_stale_socket(&path).is_err());
+        assert!(path.exists());
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn remove_stale_socket_keeps_regular_file() {
+        let path = socket_path("file");
+        std::fs::write(&path, b"").unwrap();
+
+        assert!(remove_stale_socket(&path).is_err());
+        assert!(path.exists());
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn shutdown_ends_blocking_accept() {
+        let path = socket_path("shutdown");
+        let mut milter = MilterBuilder::from_factory(|| MyMessageHandler {}).build();
+        let shutdown_handle = milter.shutdown_handle();
+
+        let (requested_tx, requested_rx) = std::sync::mpsc::channel();
+        let watched_path = path.clone();
+        thread::spawn(move || {
+            while !watched_path.exists() {
+                thread::sleep(Duration::from_millis(10));
+            }
+            shutdown_handle.shutdown();
+            requested_tx.send(Instant::now()).unwrap();
+        });
+
+        milter.run_unix(&path).unwrap();
+
+        let requested = requested_rx.recv().unwrap();
+        assert!(requested.elapsed() < Duration::from_secs(1));
+        assert!(!path.exists());
+    }
+}
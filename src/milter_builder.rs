@@ -1,6 +1,10 @@
+use std::sync::Arc;
+use std::time::Duration;
+
 use crate::message_handler::MessageHandler;
-use crate::milter::Milter;
-use crate::milter_message::MilterProtocol;
+use crate::milter::{MessageHandlerSource, Milter, MilterConfig};
+use crate::milter_message::{MacroStage, MilterActions, MilterProtocol};
+use crate::milter_socket::MilterSocket;
 
 /// Used to build a Milter.
 ///
@@ -13,7 +17,9 @@ use crate::milter_message::MilterProtocol;
 /// this information during option negotiation with the MTA.
 ///
 /// If the `set_protocol` method is not used, all functionality is enabled by default during option
-/// negotiation.
+/// negotiation. The same applies to the `set_actions` method: if it is not used, all actions
+/// offered by the MTA are accepted. Protocol flags which are not offered by the MTA are dropped
+/// during option negotiation.
 ///
 /// # Example
 /// ```
@@ -29,8 +35,8 @@ use crate::milter_message::MilterProtocol;
 ///     .build();
 /// ```
 pub struct MilterBuilder<'a> {
-    message_handler: &'a mut dyn MessageHandler,
-    protocol: Option<MilterProtocol>,
+    config: MilterConfig,
+    message_handler: MessageHandlerSource<'a>,
 }
 
 impl<'a> MilterBuilder<'a> {
@@ -50,7 +56,40 @@ impl<'a> MilterBuilder<'a> {
     ///     .build();
     /// ```
     pub fn build(self) -> Milter<'a> {
-        Milter::new(self.message_handler, self.protocol)
+        Milter::new(self.message_handler, self.config)
+    }
+
+    /// Creates a new MilterBuilder with a factory that creates a MessageHandler per connection.
+    ///
+    /// In contrast to `MilterBuilder::new`, each connection of the MTA is handled on its own
+    /// thread using its own MessageHandler, so a slow message doesn't block other connections.
+    /// The number of threads can be limited using `set_max_threads`.
+    ///
+    /// # Example
+    /// ```
+    /// use rmilter::milter_builder::MilterBuilder;
+    /// use rmilter::message_handler::MessageHandler;
+    ///
+    /// struct MyHandler {
+    ///     headers: usize,
+    /// }
+    /// impl MessageHandler for MyHandler {}
+    ///
+    /// let mut milter = MilterBuilder::from_factory(|| MyHandler { headers: 0 })
+    ///     .set_max_threads(16)
+    ///     .build();
+    /// ```
+    pub fn from_factory<F, H>(factory: F) -> Self
+    where
+        F: Fn() -> H + Send + Sync + 'static,
+        H: MessageHandler + Send + 'static,
+    {
+        Self {
+            config: MilterConfig::default(),
+            message_handler: MessageHandlerSource::Factory(Arc::new(move || {
+                Box::new(factory()) as Box<dyn MessageHandler + Send>
+            })),
+        }
     }
 
     /// Creates a new MilterBuilder with a given MessageHandler.
@@ -73,13 +112,122 @@ impl<'a> MilterBuilder<'a> {
     /// ```
     pub fn new(message_handler: &'a mut impl MessageHandler) -> Self {
         Self {
-            message_handler,
-            protocol: None,
+            config: MilterConfig::default(),
+            message_handler: MessageHandlerSource::Borrowed(message_handler),
         }
     }
 
+    /// Requests the MTA to send the given macros at the given stage (SMFIR_SETSYMLIST).
+    ///
+    /// The requested macros replace the macros defined in the MTA configuration for this stage.
+    /// Calling this method more than once for the same stage adds the macros to the list. If the
+    /// MTA doesn't support requesting macros, option negotiation fails and the connection is
+    /// closed.
+    ///
+    /// # Example
+    /// ```
+    /// use rmilter::milter_builder::MilterBuilder;
+    /// use rmilter::message_handler::MessageHandler;
+    /// use rmilter::milter_message::MacroStage;
+    ///
+    /// struct MyHandler;
+    /// impl MessageHandler for MyHandler {}
+    ///
+    /// let mut handler = MyHandler {};
+    ///
+    /// let mut milter = MilterBuilder::new(&mut handler)
+    ///     .request_macros(MacroStage::Connect, &["{client_ptr}", "{daemon_name}"])
+    ///     .request_macros(MacroStage::MailFrom, &["{auth_authen}"])
+    ///     .build();
+    /// ```
+    pub fn request_macros(mut self, stage: MacroStage, macros: &[&str]) -> Self {
+        self.config.add_macros(stage, macros);
+        self
+    }
+
+    /// Used to define the actions the milter wants to perform on messages (e.g. adding headers
+    /// or quarantining messages).
+    ///
+    /// If the MTA doesn't offer all of these actions, option negotiation fails and the connection
+    /// is closed.
+    ///
+    /// # Example
+    /// ```
+    /// use rmilter::milter_builder::MilterBuilder;
+    /// use rmilter::message_handler::MessageHandler;
+    /// use rmilter::milter_message::MilterActions;
+    ///
+    /// struct MyHandler;
+    /// impl MessageHandler for MyHandler {}
+    ///
+    /// let mut handler = MyHandler {};
+    ///
+    /// let mut milter = MilterBuilder::new(&mut handler)
+    ///     .set_actions(MilterActions::ADD_HEADERS | MilterActions::QUARANTINE)
+    ///     .build();
+    /// ```
+    pub fn set_actions(mut self, actions: MilterActions) -> Self {
+        self.config.actions = Some(actions);
+        self
+    }
+
+    /// Used to define how long the MTA may stay silent between two messages of a connection.
+    ///
+    /// If no new message is received in time, `MessageHandler::timeout` is called with
+    /// `TimeoutKind::Idle` and the connection is closed. By default, there is no idle timeout.
+    /// A timeout of zero disables the timeout.
+    ///
+    /// # Example
+    /// ```
+    /// use std::time::Duration;
+    ///
+    /// use rmilter::milter_builder::MilterBuilder;
+    /// use rmilter::message_handler::MessageHandler;
+    ///
+    /// struct MyHandler;
+    /// impl MessageHandler for MyHandler {}
+    ///
+    /// let mut handler = MyHandler {};
+    ///
+    /// let mut milter = MilterBuilder::new(&mut handler)
+    ///     .set_idle_timeout(Duration::from_secs(600))
+    ///     .build();
+    /// ```
+    pub fn set_idle_timeout(mut self, timeout: Duration) -> Self {
+        self.config.idle_timeout = Some(timeout).filter(|t| !t.is_zero());
+        self
+    }
+
+    /// Used to define the maximum number of connections handled at the same time when using
+    /// `MilterBuilder::from_factory`.
+    ///
+    /// Further connections are accepted once a running connection is closed. By default, the
+    /// number of connections is not limited.
+    ///
+    /// # Example
+    /// ```
+    /// use rmilter::milter_builder::MilterBuilder;
+    /// use rmilter::message_handler::MessageHandler;
+    ///
+    /// struct MyHandler;
+    /// impl MessageHandler for MyHandler {}
+    ///
+    /// let mut milter = MilterBuilder::from_factory(|| MyHandler {})
+    ///     .set_max_threads(16)
+    ///     .build();
+    /// ```
+    pub fn set_max_threads(mut self, max_threads: usize) -> Self {
+        self.config.max_sessions = Some(max_threads.max(1));
+        self
+    }
+
     /// Used to define the protocol for communicating with the MTA.
     ///
+    /// The `MilterProtocol::NO_REPLY_*` flags can be used to declare that the `MessageHandler`
+    /// never replies to a message part, which saves a round trip to the MTA for each of these
+    /// parts. If negotiated, the action returned by the corresponding `MessageHandler` method is
+    /// ignored.
+    ///
     /// # Example
     /// ```
     /// use rmilter::milter_builder::MilterBuilder;
@@ -90,16 +238,161 @@ impl<'a> MilterBuilder<'a> {
     /// impl MessageHandler for MyHandler {}
     ///
     /// let mut handler = MyHandler {};
-    /// let protocol = MilterProtocol::default();
+    /// let protocol = MilterProtocol::NO_HELO | MilterProtocol::NO_REPLY_HEADER;
     ///
     /// let mut milter = MilterBuilder::new(&mut handler)
     ///     .set_protocol(protocol)
     ///     .build();
     /// ```
-    pub fn set_protocol(self, protocol: MilterProtocol) -> Self {
-        Self {
-            protocol: Some(protocol),
-            ..self
-        }
+    pub fn set_protocol(mut self, protocol: MilterProtocol) -> Self {
+        self.config.protocol = Some(protocol);
+        self
+    }
+
+    /// Used to define how long the MTA may take to send the rest of a started message.
+    ///
+    /// If the message is not complete in time, `MessageHandler::timeout` is called with
+    /// `TimeoutKind::Read` and the connection is closed. By default, there is no read timeout.
+    /// A timeout of zero disables the timeout.
+    ///
+    /// # Example
+    /// ```
+    /// use std::time::Duration;
+    ///
+    /// use rmilter::milter_builder::MilterBuilder;
+    /// use rmilter::message_handler::MessageHandler;
+    ///
+    /// struct MyHandler;
+    /// impl MessageHandler for MyHandler {}
+    ///
+    /// let mut handler = MyHandler {};
+    ///
+    /// let mut milter = MilterBuilder::new(&mut handler)
+    ///     .set_read_timeout(Duration::from_secs(10))
+    ///     .build();
+    /// ```
+    pub fn set_read_timeout(mut self, timeout: Duration) -> Self {
+        self.config.read_timeout = Some(timeout).filter(|t| !t.is_zero());
+        self
+    }
+
+    /// Used to define how long running sessions may take to finish after a shutdown has been
+    /// requested using the `ShutdownHandle` (30 seconds by default).
+    ///
+    /// Connections still open after this time are closed.
+    ///
+    /// # Example
+    /// ```
+    /// use std::time::Duration;
+    ///
+    /// use rmilter::milter_builder::MilterBuilder;
+    /// use rmilter::message_handler::MessageHandler;
+    ///
+    /// struct MyHandler;
+    /// impl MessageHandler for MyHandler {}
+    ///
+    /// let mut milter = MilterBuilder::from_factory(|| MyHandler {})
+    ///     .set_shutdown_timeout(Duration::from_secs(60))
+    ///     .build();
+    /// ```
+    pub fn set_shutdown_timeout(mut self, timeout: Duration) -> Self {
+        self.config.shutdown_timeout = Some(timeout);
+        self
+    }
+
+    /// Used to define the socket the milter listens on when using `Milter::start`.
+    ///
+    /// See `MilterSocket` for the supported socket formats.
+    ///
+    /// # Example
+    /// ```
+    /// use rmilter::milter_builder::MilterBuilder;
+    /// use rmilter::message_handler::MessageHandler;
+    ///
+    /// struct MyHandler;
+    /// impl MessageHandler for MyHandler {}
+    ///
+    /// let mut handler = MyHandler {};
+    ///
+    /// let mut milter = MilterBuilder::new(&mut handler)
+    ///     .set_socket("unix:/var/run/milter.sock".parse().expect("Invalid socket"))
+    ///     .build();
+    /// ```
+    pub fn set_socket(mut self, socket: MilterSocket) -> Self {
+        self.config.socket = Some(socket);
+        self
+    }
+
+    /// Used to define the owner of the socket file created by `Milter::run_unix`.
+    ///
+    /// - `uid` is the user id of the new owner (unchanged if `None`).
+    /// - `gid` is the group id of the new owner (unchanged if `None`).
+    ///
+    /// # Example
+    /// ```
+    /// use rmilter::milter_builder::MilterBuilder;
+    /// use rmilter::message_handler::MessageHandler;
+    ///
+    /// struct MyHandler;
+    /// impl MessageHandler for MyHandler {}
+    ///
+    /// let mut handler = MyHandler {};
+    ///
+    /// let mut milter = MilterBuilder::new(&mut handler)
+    ///     .set_socket_owner(None, Some(89))
+    ///     .build();
+    /// ```
+    pub fn set_socket_owner(mut self, uid: Option<u32>, gid: Option<u32>) -> Self {
+        self.config.socket_owner = (uid, gid);
+        self
+    }
+
+    /// Used to define the permissions (e.g. `0o660`) of the socket file created by
+    /// `Milter::run_unix`.
+    ///
+    /// # Example
+    /// ```
+    /// use rmilter::milter_builder::MilterBuilder;
+    /// use rmilter::message_handler::MessageHandler;
+    ///
+    /// struct MyHandler;
+    /// impl MessageHandler for MyHandler {}
+    ///
+    /// let mut handler = MyHandler {};
+    ///
+    /// let mut milter = MilterBuilder::new(&mut handler)
+    ///     .set_socket_permissions(0o660)
+    ///     .build();
+    /// ```
+    pub fn set_socket_permissions(mut self, mode: u32) -> Self {
+        self.config.socket_permissions = Some(mode);
+        self
+    }
+
+    /// Used to define how long sending a reply to the MTA may take.
+    ///
+    /// If a reply can't be sent in time, `MessageHandler::timeout` is called with
+    /// `TimeoutKind::Write` and the connection is closed. By default, there is no write timeout.
+    /// A timeout of zero disables the timeout.
+    ///
+    /// # Example
+    /// ```
+    /// use std::time::Duration;
+    ///
+    /// use rmilter::milter_builder::MilterBuilder;
+    /// use rmilter::message_handler::MessageHandler;
+    ///
+    /// struct MyHandler;
+    /// impl MessageHandler for MyHandler {}
+    ///
+    /// let mut handler = MyHandler {};
+    ///
+    /// let mut milter = MilterBuilder::new(&mut handler)
+    ///     .set_write_timeout(Duration::from_secs(10))
+    ///     .build();
+    /// ```
+    pub fn set_write_timeout(mut self, timeout: Duration) -> Self {
+        self.config.write_timeout = Some(timeout).filter(|t| !t.is_zero());
+        self
     }
 }
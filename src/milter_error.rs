@@ -1,11 +1,23 @@
 use std::error::Error;
 use std::fmt::{Display, Formatter};
 
+use crate::milter_message::MilterActions;
+
 /// Errors defined in the `rmilter` crate
 #[derive(Debug)]
 pub enum MilterError {
+    /// An action was requested that has not been negotiated with the MTA
+    ActionNotNegotiated(MilterActions),
+    /// A string to be sent to the MTA contains a NUL byte
+    EmbeddedNul(String),
+    /// The options offered by the MTA are incompatible with the options requested by the milter
+    IncompatibleOptions(String),
     /// An incomplete message was received by rmilter (e.g. missing non-optional fields)
     IncompleteMessage,
+    /// An invalid SMTP reply code was defined
+    InvalidReplyCode(String),
+    /// An invalid socket was defined
+    InvalidSocket(String),
     /// An `std::io::Error` occured
     IoError(std::io::Error),
     /// A message was received by rmilter that doesn't contain a message identifier
@@ -21,7 +33,12 @@ pub enum MilterError {
 impl Display for MilterError {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         match self {
+            MilterError::ActionNotNegotiated(a) => write!(f, "action not negotiated: {:?}", a),
+            MilterError::EmbeddedNul(s) => write!(f, "string contains a NUL byte: {:?}", s),
+            MilterError::IncompatibleOptions(s) => write!(f, "incompatible options: {}", s),
             MilterError::IncompleteMessage => write!(f, "incomplete message"),
+            MilterError::InvalidReplyCode(s) => write!(f, "invalid reply code: {}", s),
+            MilterError::InvalidSocket(s) => write!(f, "invalid socket: {}", s),
             MilterError::IoError(e) => e.fmt(f),
             MilterError::MissingMessageIdentifier => write!(f, "missing message identifier"),
             MilterError::TryFromIntError(e) => e.fmt(f),
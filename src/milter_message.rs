@@ -1,15 +1,14 @@
 use std::convert::{TryFrom, TryInto};
 
 use crate::accept_reject_action::AcceptRejectAction;
+use crate::encoded_word;
 use crate::milter_error::MilterError;
 
-use regex::Regex;
-
 #[derive(Debug)]
 pub(crate) enum MilterMessage {
     AbortFilterChecks,
     BodyChunk {
-        value: String,
+        value: Vec<u8>,
     },
     ConnectionInformation {
         hostname: String,
@@ -17,6 +16,7 @@ pub(crate) enum MilterMessage {
         port: u16,
         address: String,
     },
+    Data,
     DefineMacros {
         cmdcode: char,
         macros: Vec<MilterMacro>,
@@ -24,8 +24,7 @@ pub(crate) enum MilterMessage {
     EndOfBody,
     EndOfHeader,
     Header {
-        name: String,
-        value: String,
+        header: MilterHeader,
     },
     Helo {
         msg: String,
@@ -40,10 +39,14 @@ pub(crate) enum MilterMessage {
         protocol: MilterProtocol,
     },
     QuitCommunication,
+    QuitNewConnection,
     RecipientInformation {
         recipient: String,
         args: Vec<String>,
     },
+    UnknownCommand {
+        command: String,
+    },
 }
 
 impl TryFrom<&[u8]> for MilterMessage {
@@ -53,7 +56,7 @@ impl TryFrom<&[u8]> for MilterMessage {
         match value {
             [b'A'] => Ok(MilterMessage::AbortFilterChecks),
             [b'B', rest @ ..] => Ok(MilterMessage::BodyChunk {
-                value: String::from_utf8_lossy(rest).into(),
+                value: rest.to_vec(),
             }),
             [b'C', rest @ ..] => {
                 let hostname_end = rest
@@ -93,13 +96,11 @@ impl TryFrom<&[u8]> for MilterMessage {
                     } else {
                         let mut macros = Vec::with_capacity(names.len());
 
-                        for (i, name) in names {
-                            if let Some((_, value)) = values.get(i) {
-                                macros.push(MilterMacro {
-                                    name: String::from_utf8_lossy(name).into(),
-                                    value: String::from_utf8_lossy(value).into(),
-                                });
-                            }
+                        for ((_, name), (_, value)) in names.into_iter().zip(values) {
+                            macros.push(MilterMacro {
+                                name: String::from_utf8_lossy(name).into(),
+                                value: String::from_utf8_lossy(value).into(),
+                            });
                         }
                         Ok(MilterMessage::DefineMacros {
                             cmdcode: char::from(*cmdcode),
@@ -117,18 +118,18 @@ impl TryFrom<&[u8]> for MilterMessage {
             [b'H', rest @ ..] => Ok(MilterMessage::Helo {
                 msg: String::from_utf8_lossy(&rest[..rest.len() - 1]).into(),
             }),
+            [b'K'] => Ok(MilterMessage::QuitNewConnection),
             [b'L', rest @ ..] => {
                 let mut buf = rest.split(|b| b == &0u8);
                 let name = buf.next().ok_or(MilterError::IncompleteMessage)?;
                 let value = buf.next().ok_or(MilterError::IncompleteMessage)?;
 
                 Ok(MilterMessage::Header {
-                    name: String::from_utf8_lossy(name).into(),
-                    value: decode(String::from_utf8_lossy(value)),
+                    header: MilterHeader::new(&String::from_utf8_lossy(name), value),
                 })
             }
             [b'M', rest @ ..] => {
-                let mut buf = rest.split(|b| b == &0u8);
+                let mut buf = rest.strip_suffix(&[0]).unwrap_or(rest).split(|b| b == &0u8);
                 let sender =
                     String::from_utf8_lossy(buf.next().ok_or(MilterError::IncompleteMessage)?);
 
@@ -153,7 +154,7 @@ impl TryFrom<&[u8]> for MilterMessage {
             }),
             [b'Q'] => Ok(MilterMessage::QuitCommunication),
             [b'R', rest @ ..] => {
-                let mut buf = rest.split(|b| b == &0u8);
+                let mut buf = rest.strip_suffix(&[0]).unwrap_or(rest).split(|b| b == &0u8);
                 let recipient =
                     String::from_utf8_lossy(buf.next().ok_or(MilterError::IncompleteMessage)?);
 
@@ -166,6 +167,17 @@ impl TryFrom<&[u8]> for MilterMessage {
                     args,
                 })
             }
+            [b'T'] => Ok(MilterMessage::Data),
+            [b'U', rest @ ..] => {
+                let command = rest
+                    .split(|b| b == &0u8)
+                    .next()
+                    .ok_or(MilterError::IncompleteMessage)?;
+
+                Ok(MilterMessage::UnknownCommand {
+                    command: String::from_utf8_lossy(command).into(),
+                })
+            }
             [identifier, ..] => Err(MilterError::UnknowMessageIdentifier(char::from(
                 *identifier,
             ))),
@@ -174,8 +186,81 @@ impl TryFrom<&[u8]> for MilterMessage {
     }
 }
 
+/// A header of the message sent by the MTA.
+///
+/// Contains the exact bytes of the value as well as the value with decoded RFC 2047 encoded
+/// words (e.g. `=?utf-8?Q?f=C3=BCr?=`, see `encoded_word::decode`).
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MilterHeader {
+    name: String,
+    raw_value: Vec<u8>,
+    value: String,
+    decoded: bool,
+}
+
+impl MilterHeader {
+    /// Returns `true` if the value contained encoded words that have been decoded.
+    ///
+    /// # Example:
+    /// ```
+    /// use rmilter::milter_message::MilterHeader;
+    ///
+    /// let header = MilterHeader::new("Subject", b"=?utf-8?Q?f=C3=BCr?=");
+    ///
+    /// assert!(header.is_decoded());
+    /// assert!(!MilterHeader::new("Subject", b"plain").is_decoded());
+    /// ```
+    pub fn is_decoded(&self) -> bool {
+        self.decoded
+    }
+
+    /// Returns the name of the header.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Creates a header from its name and the raw bytes of its value.
+    ///
+    /// The value is decoded the same way as the headers received from the MTA, which is useful
+    /// for testing `MessageHandler` implementations.
+    ///
+    /// # Example:
+    /// ```
+    /// use rmilter::milter_message::MilterHeader;
+    ///
+    /// let header = MilterHeader::new("Subject", b"=?utf-8?Q?f=C3=BCr?= you");
+    ///
+    /// assert_eq!("Subject", header.name());
+    /// assert_eq!(b"=?utf-8?Q?f=C3=BCr?= you", header.raw_value());
+    /// assert_eq!("für you", header.value());
+    /// ```
+    pub fn new(name: &str, raw_value: &[u8]) -> Self {
+        let (value, decoded) = match encoded_word::decode(raw_value) {
+            Some(value) => (value, true),
+            None => (String::from_utf8_lossy(raw_value).into_owned(), false),
+        };
+
+        Self {
+            name: name.into(),
+            raw_value: raw_value.to_vec(),
+            value,
+            decoded,
+        }
+    }
+
+    /// Returns the exact bytes of the value as sent by the MTA.
+    pub fn raw_value(&self) -> &[u8] {
+        &self.raw_value
+    }
+
+    /// Returns the decoded value (invalid UTF-8 sequences are replaced).
+    pub fn value(&self) -> &str {
+        &self.value
+    }
+}
+
 /// A macro defined by the MTA.
-#[derive(Debug)]
+#[derive(Clone, Debug, PartialEq, Eq)]
 pub struct MilterMacro {
     /// The name of the macro.
     name: String,
@@ -183,8 +268,72 @@ pub struct MilterMacro {
     value: String,
 }
 
+impl MilterMacro {
+    /// Returns the name of the macro as sent by the MTA (e.g. `i` or `{auth_authen}`).
+    ///
+    /// # Example:
+    /// ```
+    /// use rmilter::milter_message::MilterMacro;
+    ///
+    /// assert_eq!("{daemon_name}", MilterMacro::new("{daemon_name}", "smtpd").name());
+    /// ```
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Creates a macro with the given name and value.
+    ///
+    /// # Example:
+    /// ```
+    /// use rmilter::milter_message::MilterMacro;
+    ///
+    /// let queue_id = MilterMacro::new("i", "4FDB12C0A3");
+    ///
+    /// assert_eq!("i", queue_id.name());
+    /// assert_eq!("4FDB12C0A3", queue_id.value());
+    /// ```
+    pub fn new(name: &str, value: &str) -> Self {
+        Self {
+            name: name.into(),
+            value: value.into(),
+        }
+    }
+
+    /// Returns the value of the macro.
+    ///
+    /// # Example:
+    /// ```
+    /// use rmilter::milter_message::MilterMacro;
+    ///
+    /// assert_eq!("smtpd", MilterMacro::new("{daemon_name}", "smtpd").value());
+    /// ```
+    pub fn value(&self) -> &str {
+        &self.value
+    }
+}
+
+/// The stages for which macros can be requested from the MTA (see
+/// `MilterBuilder::request_macros`).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MacroStage {
+    /// Connection information (SMFIC_CONNECT)
+    Connect = 0,
+    /// Helo message (SMFIC_HELO)
+    Helo = 1,
+    /// Mail from message (SMFIC_MAIL)
+    MailFrom = 2,
+    /// Recipient information (SMFIC_RCPT)
+    Recipient = 3,
+    /// Data command (SMFIC_DATA)
+    Data = 4,
+    /// End of body (SMFIC_BODYEOB)
+    EndOfBody = 5,
+    /// End of header (SMFIC_EOH)
+    EndOfHeader = 6,
+}
+
 /// The protocol family used (currently only Inet4 and Inet6 are supported).
-#[derive(Debug)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum ProtocolFamily {
     /// Unix socket.
     UnixSocket,
@@ -195,18 +344,28 @@ pub enum ProtocolFamily {
 }
 
 bitflags! {
-    pub(crate) struct MilterActions: u32 {
+    /// Used for defining which actions the milter may perform on a message
+    #[derive(Default)]
+    pub struct MilterActions: u32 {
         const ADD_HEADERS = 1;
         const CHANGE_BODY = 1 << 1;
         const ADD_RECIPIENTS = 1 << 2;
         const REMOVE_RECIPIENTS = 1 << 3;
         const CHANGE_HEADERS = 1 << 4;
         const QUARANTINE = 1 << 5;
+        const CHANGE_FROM = 1 << 6;
+        const ADD_RECIPIENTS_WITH_ARGS = 1 << 7;
+        const SET_MACROS_LIST = 1 << 8;
     }
 }
 
 bitflags! {
     /// Used for defining which message parts should be excluded for the Milter
+    ///
+    /// The `NO_REPLY_*` flags define for which message parts the milter doesn't send a reply,
+    /// `SKIP` allows to skip the remaining body chunks (always requested if offered by the MTA),
+    /// `RECIPIENT_REJECTED` requests rejected
+    /// recipients as well and `HEADER_LEADING_SPACE` keeps the leading space of header values.
     #[derive(Default)]
     pub struct MilterProtocol: u32 {
         const NO_CONNECT = 1;
@@ -216,9 +375,94 @@ bitflags! {
         const NO_BODY = 1 << 4;
         const NO_HEADER = 1 << 5;
         const NO_EOH = 1 << 6;
+        const NO_REPLY_HEADER = 1 << 7;
+        const NO_UNKNOWN = 1 << 8;
+        const NO_DATA = 1 << 9;
+        const SKIP = 1 << 10;
+        const RECIPIENT_REJECTED = 1 << 11;
+        const NO_REPLY_CONNECT = 1 << 12;
+        const NO_REPLY_HELO = 1 << 13;
+        const NO_REPLY_MAIL = 1 << 14;
+        const NO_REPLY_RECIPIENT = 1 << 15;
+        const NO_REPLY_DATA = 1 << 16;
+        const NO_REPLY_UNKNOWN = 1 << 17;
+        const NO_REPLY_EOH = 1 << 18;
+        const NO_REPLY_BODY = 1 << 19;
+        const HEADER_LEADING_SPACE = 1 << 20;
+    }
+}
+
+/// The highest milter protocol version supported by rmilter.
+const MILTER_VERSION: u32 = 6;
+
+/// The lowest milter protocol version supported by rmilter.
+const MIN_MILTER_VERSION: u32 = 2;
+
+/// The options agreed on with the MTA during option negotiation (SMFIC_OPTNEG).
+#[derive(Debug, Default)]
+pub(crate) struct NegotiatedOptions {
+    pub actions: MilterActions,
+    pub macros: Vec<(MacroStage, Vec<String>)>,
+    pub protocol: MilterProtocol,
+    pub version: u32,
+}
+
+impl NegotiatedOptions {
+    /// Negotiates the options offered by the MTA with the options requested by the milter.
+    ///
+    /// The negotiated protocol flags are the intersection of the offered and requested flags. If
+    /// the milter requested actions, all of them have to be offered by the MTA. Otherwise all
+    /// offered actions are used. Requesting macros requires `MilterActions::SET_MACROS_LIST`.
+    pub(crate) fn negotiate(
+        offered_version: u32,
+        offered_actions: MilterActions,
+        offered_protocol: MilterProtocol,
+        requested_actions: Option<MilterActions>,
+        requested_protocol: MilterProtocol,
+        requested_macros: &[(MacroStage, Vec<String>)],
+    ) -> Result<Self, MilterError> {
+        if offered_version < MIN_MILTER_VERSION {
+            return Err(MilterError::IncompatibleOptions(format!(
+                "MTA protocol version {} is not supported",
+                offered_version
+            )));
+        }
+
+        let mut actions = match requested_actions {
+            Some(requested_actions) if !offered_actions.contains(requested_actions) => {
+                return Err(MilterError::IncompatibleOptions(format!(
+                    "MTA doesn't offer the requested actions {:?}",
+                    requested_actions - offered_actions
+                )));
+            }
+            Some(requested_actions) => requested_actions,
+            None => offered_actions,
+        };
+
+        if !requested_macros.is_empty() {
+            if !offered_actions.contains(MilterActions::SET_MACROS_LIST) {
+                return Err(MilterError::IncompatibleOptions(
+                    "MTA doesn't support requesting macros".into(),
+                ));
+            }
+
+            actions |= MilterActions::SET_MACROS_LIST;
+        }
+
+        Ok(Self {
+            actions,
+            macros: requested_macros.to_vec(),
+            // Skipping only takes effect when AcceptRejectAction::Skip is returned, so it is
+            // always requested
+            protocol: offered_protocol & (requested_protocol | MilterProtocol::SKIP),
+            version: offered_version.min(MILTER_VERSION),
+        })
     }
 }
 
+/// Maximum size of a single body chunk sent to the MTA (see MILTER_CHUNK_SIZE in libmilter).
+pub(crate) const MAX_BODY_CHUNK_SIZE: usize = 65535;
+
 #[derive(Debug)]
 pub(crate) struct ResponseMessage {
     content: Vec<u8>,
@@ -252,6 +496,24 @@ impl From<AcceptRejectAction> for ResponseMessage {
                     buf.push(b'r');
                     buf
                 }
+                AcceptRejectAction::ReplyCode(reply_code) => {
+                    let mut reply = reply_code.encode().into_bytes();
+                    // The length of a ReplyCode is limited, so it always fits into u32
+                    let length = reply.len() as u32 + 2;
+
+                    let mut buf = Vec::with_capacity(reply.len() + 6);
+                    buf.append(&mut u32::to_be_bytes(length).to_vec());
+                    buf.push(b'y');
+                    buf.append(&mut reply);
+                    buf.push(0);
+                    buf
+                }
+                AcceptRejectAction::Skip => {
+                    let mut buf = Vec::with_capacity(5);
+                    buf.append(&mut u32::to_be_bytes(1).to_vec());
+                    buf.push(b's');
+                    buf
+                }
                 AcceptRejectAction::Tempfail => {
                     let mut buf = Vec::with_capacity(5);
                     buf.append(&mut u32::to_be_bytes(1).to_vec());
@@ -264,120 +526,144 @@ impl From<AcceptRejectAction> for ResponseMessage {
 }
 
 impl ResponseMessage {
-    pub(crate) fn get_content(&self) -> &[u8] {
-        &self.content
-    }
+    fn new(identifier: u8, mut data: Vec<u8>) -> Result<Self, MilterError> {
+        let length: u32 = (data.len() + 1).try_into()?;
 
-    pub(crate) fn option_negotiation(
-        version: u32,
-        actions: MilterActions,
-        protocol: &MilterProtocol,
-    ) -> Self {
-        // OPTNEG buffer length is always 17
-        let mut buf = Vec::with_capacity(17);
+        let mut buf = Vec::with_capacity(data.len() + 5);
+        buf.append(&mut length.to_be_bytes().to_vec());
+        buf.push(identifier);
+        buf.append(&mut data);
 
-        // OPTNEG length is always 13
-        let mut length = u32::to_be_bytes(13).to_vec();
+        Ok(Self { content: buf })
+    }
 
-        buf.append(&mut length);
-        buf.push(b'O');
+    /// Add a header at the end of the existing headers (SMFIR_ADDHEADER).
+    pub(crate) fn add_header(name: &str, value: &str) -> Result<Self, MilterError> {
+        let mut data = Vec::with_capacity(name.len() + value.len() + 2);
+        push_c_string(&mut data, name)?;
+        push_c_string(&mut data, value)?;
 
-        buf.append(&mut version.to_be_bytes().to_vec());
-        buf.append(&mut actions.bits().to_be_bytes().to_vec());
-        buf.append(&mut protocol.bits.to_be_bytes().to_vec());
+        Self::new(b'h', data)
+    }
+
+    /// Add a recipient to the envelope (SMFIR_ADDRCPT).
+    pub(crate) fn add_recipient(recipient: &str) -> Result<Self, MilterError> {
+        let mut data = Vec::with_capacity(recipient.len() + 1);
+        push_c_string(&mut data, recipient)?;
 
-        Self { content: buf }
+        Self::new(b'+', data)
     }
-}
 
-fn decode<S: AsRef<str>>(s: S) -> String {
-    lazy_static! {
-        static ref REGEX: Regex =
-            Regex::new(r"(?P<start>=\?)(?P<charset>.*)\?(?P<transfer_encoding>.*)\?(?P<encoded_value>.*)(?P<end>\?=)")
-                .expect("Can't compile regex for decoding");
+    /// Add a recipient including ESMTP arguments to the envelope (SMFIR_ADDRCPT_PAR).
+    pub(crate) fn add_recipient_with_args(
+        recipient: &str,
+        args: &str,
+    ) -> Result<Self, MilterError> {
+        let mut data = Vec::with_capacity(recipient.len() + args.len() + 2);
+        push_c_string(&mut data, recipient)?;
+        push_c_string(&mut data, args)?;
+
+        Self::new(b'2', data)
     }
 
-    let mut res = String::with_capacity(s.as_ref().len());
-    let mut last_end = 0;
+    /// Change (or delete, if `value` is empty) the `index`-th occurrence of a header
+    /// (SMFIR_CHGHEADER).
+    pub(crate) fn change_header(name: &str, index: u32, value: &str) -> Result<Self, MilterError> {
+        let mut data = Vec::with_capacity(name.len() + value.len() + 6);
+        data.append(&mut index.to_be_bytes().to_vec());
+        push_c_string(&mut data, name)?;
+        push_c_string(&mut data, value)?;
 
-    for capture in REGEX.captures_iter(s.as_ref()) {
-        if let Some(decoded_string) = decode_captures(capture) {
-            if decoded_string.start > last_end {
-                let rest: String = s
-                    .as_ref()
-                    .chars()
-                    .skip(last_end)
-                    .take(decoded_string.start - last_end)
-                    .collect();
-                res.push_str(&rest);
-            }
+        Self::new(b'm', data)
+    }
+
+    /// Change the envelope sender, optionally including ESMTP arguments (SMFIR_CHGFROM).
+    pub(crate) fn change_from(sender: &str, args: Option<&str>) -> Result<Self, MilterError> {
+        let mut data = Vec::with_capacity(sender.len() + args.map(str::len).unwrap_or(0) + 2);
+        push_c_string(&mut data, sender)?;
 
-            res.push_str(&decoded_string.value);
-            last_end = decoded_string.end;
+        if let Some(args) = args {
+            push_c_string(&mut data, args)?;
         }
+
+        Self::new(b'e', data)
     }
 
-    // Append rest (if any)
-    let input_len = s.as_ref().chars().count();
-    if input_len > last_end {
-        let rest: String = s
-            .as_ref()
-            .chars()
-            .skip(last_end)
-            .take(input_len - last_end)
-            .collect();
-        res.push_str(&rest);
+    /// Remove a recipient from the envelope (SMFIR_DELRCPT).
+    pub(crate) fn delete_recipient(recipient: &str) -> Result<Self, MilterError> {
+        let mut data = Vec::with_capacity(recipient.len() + 1);
+        push_c_string(&mut data, recipient)?;
+
+        Self::new(b'-', data)
     }
 
-    res
-}
+    pub(crate) fn get_content(&self) -> &[u8] {
+        &self.content
+    }
 
-fn decode_captures(c: regex::Captures) -> Option<DecodedString> {
-    let start = c.name("start")?.start();
-    let end = c.name("end")?.end();
-    let charset = c.name("charset")?;
-
-    if let Some(charset) = charset::Charset::for_label_no_replacement(charset.as_str().as_bytes()) {
-        let transfer_encoding = c.name("transfer_encoding")?.as_str();
-        let encoded_value = c.name("encoded_value")?.as_str();
-
-        let decoded = match transfer_encoding {
-            "b" | "B" => Some(base64::decode(encoded_value).ok()?),
-            "q" | "Q" => Some(
-                quoted_printable::decode(
-                    encoded_value.replace("_", " "),
-                    quoted_printable::ParseMode::Robust,
-                )
-                .ok()?,
-            ),
-            _ => None,
-        };
+    /// Insert a header at position `index` of the existing headers (SMFIR_INSHEADER).
+    pub(crate) fn insert_header(name: &str, index: u32, value: &str) -> Result<Self, MilterError> {
+        let mut data = Vec::with_capacity(name.len() + value.len() + 6);
+        data.append(&mut index.to_be_bytes().to_vec());
+        push_c_string(&mut data, name)?;
+        push_c_string(&mut data, value)?;
+
+        Self::new(b'i', data)
+    }
 
-        if let Some(decoded) = decoded {
-            let (decoded, _) = charset.decode_without_bom_handling(&decoded);
+    pub(crate) fn option_negotiation(options: &NegotiatedOptions) -> Result<Self, MilterError> {
+        let mut data = Vec::with_capacity(12);
 
-            Some(DecodedString {
-                start,
-                end,
-                value: decoded.to_string(),
-            })
-        } else {
-            None
+        data.append(&mut options.version.to_be_bytes().to_vec());
+        data.append(&mut options.actions.bits().to_be_bytes().to_vec());
+        data.append(&mut options.protocol.bits().to_be_bytes().to_vec());
+
+        // Requested macros are appended as stage and space separated list of macro names
+        for (stage, macros) in &options.macros {
+            data.append(&mut (*stage as u32).to_be_bytes().to_vec());
+            push_c_string(&mut data, &macros.join(" "))?;
         }
-    } else {
-        None
+
+        Self::new(b'O', data)
+    }
+
+    /// Inform the MTA that the milter is still working on the message (SMFIR_PROGRESS).
+    pub(crate) fn progress() -> Result<Self, MilterError> {
+        Self::new(b'p', Vec::new())
+    }
+
+    /// Quarantine the message with the given reason (SMFIR_QUARANTINE).
+    pub(crate) fn quarantine(reason: &str) -> Result<Self, MilterError> {
+        let mut data = Vec::with_capacity(reason.len() + 1);
+        push_c_string(&mut data, reason)?;
+
+        Self::new(b'q', data)
+    }
+
+    /// Replace the body with `chunk` (SMFIR_REPLBODY).
+    ///
+    /// Chunks larger than `MAX_BODY_CHUNK_SIZE` have to be split before.
+    pub(crate) fn replace_body(chunk: &[u8]) -> Result<Self, MilterError> {
+        Self::new(b'b', chunk.to_vec())
     }
 }
 
-struct DecodedString {
-    pub start: usize,
-    pub end: usize,
-    pub value: String,
+/// Appends `s` as NUL-terminated string, which must not contain a NUL byte itself.
+fn push_c_string(buf: &mut Vec<u8>, s: &str) -> Result<(), MilterError> {
+    if s.contains('\0') {
+        return Err(MilterError::EmbeddedNul(s.into()));
+    }
+
+    buf.extend_from_slice(s.as_bytes());
+    buf.push(0);
+
+    Ok(())
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::accept_reject_action::ReplyCode;
 
     #[test]
     fn create_milter_actions_add_recipients() {
@@ -425,42 +711,296 @@ mod tests {
     }
 
     #[test]
-    fn decode_utf8_base64() {
-        // Taken from an actual spam mail which contained padding chars
-        let input = "=?utf-8?B?IkjDtmhsZSBkZXIgTMO2d2VuIiBTeXN0ZW0gbWFjaHQgRGV1dHNjaGUgQsO8cmdlciByZWljaCE=?=";
-        let res = decode(input);
-        let comp = "\"Höhle der Löwen\" System macht Deutsche Bürger reich!";
+    fn parse_body_chunk_keeps_bytes() {
+        let res = MilterMessage::try_from(&b"Bcaf\xe9\x00\xff"[..]).unwrap();
 
-        assert_eq!(comp, res);
+        assert!(matches!(res, MilterMessage::BodyChunk { value } if value == b"caf\xe9\x00\xff"));
     }
 
     #[test]
-    fn decode_utf8_base64_with_not_encoded() {
-        // Taken from an actual spam mail and added 'not encoded' to test that we keep non-encoded
-        // data
-        let input = "not encoded=?utf-8?B?4oCeSMO2aGxlIGRlciBMw7Z3ZW7igJwgU3lzdGVtIG1hY2h0IERldXRzY2hlIELDvHJnZXIgcmVpY2gh?=not encoded";
-        let res = decode(input);
-        let comp = "not encoded„Höhle der Löwen“ System macht Deutsche Bürger reich!not encoded";
+    fn parse_header_keeps_raw_value() {
+        let res = MilterMessage::try_from(&b"LSubject\x00=?utf-8?B?w7w=?= \xfc\x00"[..]).unwrap();
+
+        match res {
+            MilterMessage::Header { header } => {
+                assert_eq!("Subject", header.name());
+                assert_eq!(b"=?utf-8?B?w7w=?= \xfc", header.raw_value());
+                assert_eq!("ü \u{fffd}", header.value());
+                assert!(header.is_decoded());
+            }
+            _ => panic!("Unexpected message: {:?}", res),
+        }
+    }
 
-        assert_eq!(comp, res);
+    #[test]
+    fn parse_data() {
+        let res = MilterMessage::try_from(&b"T"[..]).unwrap();
+
+        assert!(matches!(res, MilterMessage::Data));
     }
 
-    /// Used for testing that we keep the original input with broken encoding
     #[test]
-    fn decode_utf8_base64_broken_encoding() {
-        let input =
-            "not encoded=?utf-8?B?w7Z3ZW7igJ2h0IERldXRzY2hlIELDvHJnZXIgcmVpY2gh?=not encoded";
-        let res = decode(input);
+    fn parse_define_macros() {
+        let res = MilterMessage::try_from(&b"DMi\x004FDB12C0A3\x00{auth_authen}\x00alice\x00"[..])
+            .unwrap();
+
+        match res {
+            MilterMessage::DefineMacros { cmdcode, macros } => {
+                let comp = vec![
+                    MilterMacro::new("i", "4FDB12C0A3"),
+                    MilterMacro::new("{auth_authen}", "alice"),
+                ];
+
+                assert_eq!('M', cmdcode);
+                assert_eq!(comp, macros);
+            }
+            _ => panic!("Unexpected message: {:?}", res),
+        }
+    }
 
-        assert_eq!(input, res);
+    #[test]
+    fn parse_mail_from_with_args() {
+        let res =
+            MilterMessage::try_from(&b"M<alice@example.org>\x00SIZE=1024\x00BODY=8BITMIME\x00"[..])
+                .unwrap();
+
+        match res {
+            MilterMessage::MailFrom { sender, args } => {
+                assert_eq!("<alice@example.org>", sender);
+                assert_eq!(vec!["SIZE=1024", "BODY=8BITMIME"], args);
+            }
+            _ => panic!("Unexpected message: {:?}", res),
+        }
+
+        let res = MilterMessage::try_from(&b"R<bob@example.org>\x00"[..]).unwrap();
+
+        assert!(matches!(res, MilterMessage::RecipientInformation { args, .. } if args.is_empty()));
     }
 
     #[test]
-    fn decode_utf8_quoted_printable() {
-        let input = "=?utf-8?Q?Endlich_was_extrem_hartes_f=C3=BCr_Sie.?=";
-        let res = decode(input);
-        let comp = "Endlich was extrem hartes für Sie.";
+    fn parse_quit_new_connection() {
+        let res = MilterMessage::try_from(&b"K"[..]).unwrap();
 
-        assert_eq!(comp, res);
+        assert!(matches!(res, MilterMessage::QuitNewConnection));
+    }
+
+    #[test]
+    fn parse_unknown_command() {
+        let res = MilterMessage::try_from(&b"UXFOO bar\x00"[..]).unwrap();
+
+        assert!(matches!(res, MilterMessage::UnknownCommand { command } if command == "XFOO bar"));
+    }
+
+    #[test]
+    fn negotiate_intersects_protocol() {
+        let res = NegotiatedOptions::negotiate(
+            6,
+            MilterActions::all(),
+            MilterProtocol::NO_HELO | MilterProtocol::SKIP,
+            None,
+            MilterProtocol::SKIP | MilterProtocol::NO_REPLY_HEADER,
+            &[],
+        )
+        .unwrap();
+
+        assert_eq!(6, res.version);
+        assert_eq!(MilterActions::all(), res.actions);
+        assert_eq!(MilterProtocol::SKIP, res.protocol);
+    }
+
+    #[test]
+    fn negotiate_requests_offered_skip() {
+        let res = NegotiatedOptions::negotiate(
+            6,
+            MilterActions::all(),
+            MilterProtocol::NO_HELO | MilterProtocol::SKIP,
+            None,
+            MilterProtocol::NO_HELO,
+            &[],
+        )
+        .unwrap();
+
+        assert_eq!(MilterProtocol::NO_HELO | MilterProtocol::SKIP, res.protocol);
+    }
+
+    #[test]
+    fn negotiate_uses_lower_version() {
+        let res = NegotiatedOptions::negotiate(
+            2,
+            MilterActions::ADD_HEADERS,
+            MilterProtocol::NO_CONNECT,
+            Some(MilterActions::ADD_HEADERS),
+            MilterProtocol::empty(),
+            &[],
+        )
+        .unwrap();
+
+        assert_eq!(2, res.version);
+        assert_eq!(MilterActions::ADD_HEADERS, res.actions);
+    }
+
+    #[test]
+    fn negotiate_fails_for_old_version() {
+        let res = NegotiatedOptions::negotiate(
+            1,
+            MilterActions::all(),
+            MilterProtocol::all(),
+            None,
+            MilterProtocol::empty(),
+            &[],
+        );
+
+        assert!(matches!(res, Err(MilterError::IncompatibleOptions(_))));
+    }
+
+    #[test]
+    fn negotiate_fails_for_missing_actions() {
+        let res = NegotiatedOptions::negotiate(
+            6,
+            MilterActions::ADD_HEADERS,
+            MilterProtocol::all(),
+            Some(MilterActions::ADD_HEADERS | MilterActions::CHANGE_FROM),
+            MilterProtocol::empty(),
+            &[],
+        );
+
+        assert!(matches!(res, Err(MilterError::IncompatibleOptions(_))));
+    }
+
+    #[test]
+    fn negotiate_fails_for_unsupported_macros() {
+        let res = NegotiatedOptions::negotiate(
+            6,
+            MilterActions::ADD_HEADERS,
+            MilterProtocol::all(),
+            None,
+            MilterProtocol::empty(),
+            &[(MacroStage::Connect, vec!["{client_ptr}".into()])],
+        );
+
+        assert!(matches!(res, Err(MilterError::IncompatibleOptions(_))));
+    }
+
+    #[test]
+    fn response_message_add_header() {
+        let res = ResponseMessage::add_header("X-Spam-Status", "No").unwrap();
+        let comp = b"\x00\x00\x00\x12hX-Spam-Status\x00No\x00";
+
+        assert_eq!(&comp[..], res.get_content());
+    }
+
+    #[test]
+    fn response_message_rejects_embedded_nul() {
+        assert!(matches!(
+            ResponseMessage::add_header("X-Spam-Status", "No\0Yes"),
+            Err(MilterError::EmbeddedNul(_))
+        ));
+        assert!(matches!(
+            ResponseMessage::change_header("Subject\0", 1, "Hello"),
+            Err(MilterError::EmbeddedNul(_))
+        ));
+        assert!(matches!(
+            ResponseMessage::add_recipient_with_args("<a@b.c>", "NOTIFY=NEVER\0"),
+            Err(MilterError::EmbeddedNul(_))
+        ));
+        assert!(matches!(
+            ResponseMessage::change_from("<a@b.c>", Some("\0")),
+            Err(MilterError::EmbeddedNul(_))
+        ));
+        assert!(matches!(
+            ResponseMessage::quarantine("Vi\0rus"),
+            Err(MilterError::EmbeddedNul(_))
+        ));
+    }
+
+    #[test]
+    fn response_message_add_recipient_with_args() {
+        let res = ResponseMessage::add_recipient_with_args("<a@b.c>", "NOTIFY=NEVER").unwrap();
+        let comp = b"\x00\x00\x00\x16\x32<a@b.c>\x00NOTIFY=NEVER\x00";
+
+        assert_eq!(&comp[..], res.get_content());
+    }
+
+    #[test]
+    fn response_message_change_header() {
+        let res = ResponseMessage::change_header("Subject", 2, "").unwrap();
+        let comp = b"\x00\x00\x00\x0em\x00\x00\x00\x02Subject\x00\x00";
+
+        assert_eq!(&comp[..], res.get_content());
+    }
+
+    #[test]
+    fn response_message_change_from() {
+        let res = ResponseMessage::change_from("<a@b.c>", None).unwrap();
+        let comp = b"\x00\x00\x00\x09e<a@b.c>\x00";
+
+        assert_eq!(&comp[..], res.get_content());
+
+        let res = ResponseMessage::change_from("<a@b.c>", Some("SIZE=10")).unwrap();
+        let comp = b"\x00\x00\x00\x11e<a@b.c>\x00SIZE=10\x00";
+
+        assert_eq!(&comp[..], res.get_content());
+    }
+
+    #[test]
+    fn response_message_delete_recipient() {
+        let res = ResponseMessage::delete_recipient("<a@b.c>").unwrap();
+        let comp = b"\x00\x00\x00\x09-<a@b.c>\x00";
+
+        assert_eq!(&comp[..], res.get_content());
+    }
+
+    #[test]
+    fn response_message_insert_header() {
+        let res = ResponseMessage::insert_header("X-A", 0, "b").unwrap();
+        let comp = b"\x00\x00\x00\x0bi\x00\x00\x00\x00X-A\x00b\x00";
+
+        assert_eq!(&comp[..], res.get_content());
+    }
+
+    #[test]
+    fn response_message_option_negotiation_with_macros() {
+        let options = NegotiatedOptions::negotiate(
+            6,
+            MilterActions::SET_MACROS_LIST,
+            MilterProtocol::empty(),
+            None,
+            MilterProtocol::empty(),
+            &[(
+                MacroStage::MailFrom,
+                vec!["i".into(), "{auth_authen}".into()],
+            )],
+        )
+        .unwrap();
+        let res = ResponseMessage::option_negotiation(&options).unwrap();
+        let comp = b"\x00\x00\x00\x21O\x00\x00\x00\x06\x00\x00\x01\x00\x00\x00\x00\x00\
+                     \x00\x00\x00\x02i {auth_authen}\x00";
+
+        assert_eq!(&comp[..], res.get_content());
+    }
+
+    #[test]
+    fn response_message_progress() {
+        let res = ResponseMessage::progress().unwrap();
+        let comp = b"\x00\x00\x00\x01p";
+
+        assert_eq!(&comp[..], res.get_content());
+    }
+
+    #[test]
+    fn response_message_quarantine() {
+        let res = ResponseMessage::quarantine("Virus").unwrap();
+        let comp = b"\x00\x00\x00\x07qVirus\x00";
+
+        assert_eq!(&comp[..], res.get_content());
+    }
+
+    #[test]
+    fn response_message_reply_code() {
+        let reply_code = ReplyCode::new(550, Some("5.7.1"), "No").unwrap();
+        let res = ResponseMessage::from(AcceptRejectAction::ReplyCode(reply_code));
+        let comp = b"\x00\x00\x00\x0ey550 5.7.1 No\x00";
+
+        assert_eq!(&comp[..], res.get_content());
     }
 }
@@ -0,0 +1,225 @@
+use std::fmt::{Display, Formatter};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, ToSocketAddrs};
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use crate::milter_error::MilterError;
+
+/// A socket the milter listens on, defined using the socket syntax known from sendmail and
+/// postfix.
+///
+/// The following formats are supported:
+///
+/// - `inet:port@host` or `inet:host:port` for IPv4 (`host` is optional)
+/// - `inet6:port@host` or `inet6:[host]:port` for IPv6 (`host` is optional and may be enclosed in
+///   brackets)
+/// - `unix:/path`, `local:/path` or just `/path` for unix domain sockets
+///
+/// # Example
+/// ```
+/// use rmilter::milter_socket::MilterSocket;
+///
+/// let socket: MilterSocket = "inet:8890@localhost".parse().expect("Invalid socket");
+///
+/// assert_eq!(
+///     MilterSocket::Inet {
+///         host: "localhost".into(),
+///         port: 8890
+///     },
+///     socket
+/// );
+/// ```
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum MilterSocket {
+    /// IPv4 socket
+    Inet {
+        /// The hostname or address (empty for all addresses)
+        host: String,
+        /// The port
+        port: u16,
+    },
+    /// IPv6 socket
+    Inet6 {
+        /// The hostname or address (empty for all addresses)
+        host: String,
+        /// The port
+        port: u16,
+    },
+    /// Unix domain socket
+    Unix(PathBuf),
+}
+
+impl MilterSocket {
+    /// Resolves the socket addresses of an `Inet` or `Inet6` socket.
+    ///
+    /// Only addresses of the matching address family are returned.
+    pub(crate) fn socket_addrs(&self) -> Result<Vec<SocketAddr>, MilterError> {
+        let (host, port, ipv6) = match self {
+            MilterSocket::Inet { host, port } => (host, *port, false),
+            MilterSocket::Inet6 { host, port } => (host, *port, true),
+            MilterSocket::Unix(_) => return Ok(Vec::new()),
+        };
+
+        let addrs: Vec<SocketAddr> = if host.is_empty() {
+            let ip = if ipv6 {
+                IpAddr::V6(Ipv6Addr::UNSPECIFIED)
+            } else {
+                IpAddr::V4(Ipv4Addr::UNSPECIFIED)
+            };
+            vec![SocketAddr::new(ip, port)]
+        } else {
+            (host.as_str(), port)
+                .to_socket_addrs()?
+                .filter(|addr| addr.is_ipv6() == ipv6)
+                .collect()
+        };
+
+        if addrs.is_empty() {
+            Err(MilterError::InvalidSocket(format!(
+                "no matching address found for {}",
+                self
+            )))
+        } else {
+            Ok(addrs)
+        }
+    }
+}
+
+impl Display for MilterSocket {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MilterSocket::Inet { host, port } => write!(f, "inet:{}@{}", port, host),
+            MilterSocket::Inet6 { host, port } => write!(f, "inet6:{}@{}", port, host),
+            MilterSocket::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+impl FromStr for MilterSocket {
+    type Err = MilterError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (kind, rest) = match s.find(':') {
+            Some(pos) => (&s[..pos], &s[pos + 1..]),
+            None => ("unix", s),
+        };
+
+        match kind.to_ascii_lowercase().as_str() {
+            "inet" => {
+                let (host, port) = parse_host_port(rest, false)?;
+                Ok(MilterSocket::Inet { host, port })
+            }
+            "inet6" => {
+                let (host, port) = parse_host_port(rest, true)?;
+                Ok(MilterSocket::Inet6 { host, port })
+            }
+            "unix" | "local" if !rest.is_empty() => Ok(MilterSocket::Unix(PathBuf::from(rest))),
+            _ => Err(MilterError::InvalidSocket(s.into())),
+        }
+    }
+}
+
+/// Parses `port@host` (sendmail) or `host:port` (postfix) into host and port.
+fn parse_host_port(s: &str, ipv6: bool) -> Result<(String, u16), MilterError> {
+    let (port, host) = if let Some(pos) = s.find('@') {
+        (&s[..pos], &s[pos + 1..])
+    } else if let Some(pos) = s.rfind(':') {
+        // A bare IPv6 address without port must not be split
+        if ipv6 && !s.starts_with('[') {
+            return Err(MilterError::InvalidSocket(s.into()));
+        }
+        (&s[pos + 1..], &s[..pos])
+    } else {
+        (s, "")
+    };
+
+    let port = port
+        .parse()
+        .map_err(|_| MilterError::InvalidSocket(format!("invalid port in '{}'", s)))?;
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+
+    Ok((host.into(), port))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_inet_sendmail() {
+        let res: MilterSocket = "inet:8890@localhost".parse().unwrap();
+        let comp = MilterSocket::Inet {
+            host: "localhost".into(),
+            port: 8890,
+        };
+
+        assert_eq!(comp, res);
+    }
+
+    #[test]
+    fn parse_inet_postfix() {
+        let res: MilterSocket = "inet:127.0.0.1:8890".parse().unwrap();
+        let comp = MilterSocket::Inet {
+            host: "127.0.0.1".into(),
+            port: 8890,
+        };
+
+        assert_eq!(comp, res);
+    }
+
+    #[test]
+    fn parse_inet_without_host() {
+        let res: MilterSocket = "inet:8890".parse().unwrap();
+        let comp = MilterSocket::Inet {
+            host: "".into(),
+            port: 8890,
+        };
+
+        assert_eq!(comp, res);
+    }
+
+    #[test]
+    fn parse_inet6() {
+        let comp = MilterSocket::Inet6 {
+            host: "::1".into(),
+            port: 8890,
+        };
+
+        assert_eq!(comp, "inet6:8890@[::1]".parse().unwrap());
+        assert_eq!(comp, "inet6:8890@::1".parse().unwrap());
+        assert_eq!(comp, "inet6:[::1]:8890".parse().unwrap());
+    }
+
+    #[test]
+    fn parse_unix() {
+        let comp = MilterSocket::Unix(PathBuf::from("/var/run/milter.sock"));
+
+        assert_eq!(comp, "unix:/var/run/milter.sock".parse().unwrap());
+        assert_eq!(comp, "local:/var/run/milter.sock".parse().unwrap());
+        assert_eq!(comp, "/var/run/milter.sock".parse().unwrap());
+    }
+
+    #[test]
+    fn parse_invalid() {
+        assert!("inet:port@localhost".parse::<MilterSocket>().is_err());
+        assert!("inet:70000@localhost".parse::<MilterSocket>().is_err());
+        assert!("inet6:::1".parse::<MilterSocket>().is_err());
+        assert!("unix:".parse::<MilterSocket>().is_err());
+        assert!("tcp:8890@localhost".parse::<MilterSocket>().is_err());
+    }
+
+    #[test]
+    fn socket_addrs_filters_address_family() {
+        let socket: MilterSocket = "inet6:8890@[::1]".parse().unwrap();
+
+        assert_eq!(
+            vec!["[::1]:8890".parse::<SocketAddr>().unwrap()],
+            socket.socket_addrs().unwrap()
+        );
+        assert!("inet:8890@[::1]"
+            .parse::<MilterSocket>()
+            .unwrap()
+            .socket_addrs()
+            .is_err());
+    }
+}
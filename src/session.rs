@@ -0,0 +1,786 @@
+use std::convert::{TryFrom, TryInto};
+use std::io::{ErrorKind, Read, Write};
+use std::net::{Shutdown, TcpStream};
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::accept_reject_action::AcceptRejectAction;
+use crate::message_handler::{MessageHandler, TimeoutKind};
+use crate::message_modifier::MessageModifier;
+use crate::milter::{Milter, MilterConfig};
+use crate::milter_error::MilterError;
+use crate::milter_message::{MilterMessage, MilterProtocol, NegotiatedOptions, ResponseMessage};
+use crate::session_context::{ConnectionInfo, EnvelopeAddress, SessionContext};
+use crate::shutdown_handle::ShutdownHandle;
+
+/// Handles a single connection of the MTA using a `MessageHandler`.
+pub(crate) struct Session<'a> {
+    config: &'a MilterConfig,
+    ctx: SessionContext,
+    message_handler: &'a mut dyn MessageHandler,
+}
+
+impl<'a> Session<'a> {
+    fn handle_message(
+        &mut self,
+        s: &mut (dyn Write + Send),
+        buffer: &[u8],
+    ) -> Result<bool, MilterError> {
+        let mut keep_open = true;
+
+        match MilterMessage::try_from(buffer) {
+            Ok(message) => {
+                match message {
+                    MilterMessage::AbortFilterChecks => {
+                        self.message_handler.abort_filter_checks(&self.ctx);
+                        self.ctx.reset_message();
+                    }
+                    MilterMessage::BodyChunk { value } => {
+                        let action = self.message_handler.body_chunk_raw(&self.ctx, &value);
+                        self.send_reply(s, action, MilterProtocol::NO_REPLY_BODY)?;
+                    }
+                    MilterMessage::ConnectionInformation {
+                        hostname,
+                        family,
+                        port,
+                        address,
+                    } => {
+                        self.ctx
+                            .set_connection(ConnectionInfo::new(&hostname, family, port, &address));
+                        let action = self
+                            .message_handler
+                            .connection(&self.ctx, &hostname, &family, &port, &address);
+                        self.send_reply(s, action, MilterProtocol::NO_REPLY_CONNECT)?;
+                    }
+                    MilterMessage::Data => {
+                        let action = self.message_handler.data(&self.ctx);
+                        self.send_reply(s, action, MilterProtocol::NO_REPLY_DATA)?;
+                    }
+                    MilterMessage::DefineMacros { cmdcode, macros } => {
+                        self.ctx.define_macros(cmdcode, macros.clone());
+                        self.message_handler
+                            .define_macros(&self.ctx, &cmdcode, macros);
+                    }
+                    MilterMessage::EndOfBody => {
+                        let mut modifier = MessageModifier::new(s, self.ctx.actions());
+                        let action =
+                            match self.message_handler.end_of_body(&self.ctx, &mut modifier) {
+                                AcceptRejectAction::Skip => AcceptRejectAction::Continue,
+                                action => action,
+                            };
+                        self.ctx.reset_message();
+                        Milter::send_response(s, action)?;
+                    }
+                    MilterMessage::EndOfHeader => {
+                        let action = self.message_handler.end_of_header(&self.ctx);
+                        self.send_reply(s, action, MilterProtocol::NO_REPLY_EOH)?;
+                    }
+                    MilterMessage::Header { header } => {
+                        self.ctx.add_header(header.clone());
+                        let action = self.message_handler.header_raw(&self.ctx, &header);
+                        self.send_reply(s, action, MilterProtocol::NO_REPLY_HEADER)?;
+                    }
+                    MilterMessage::Helo { msg } => {
+                        self.ctx.set_helo(&msg);
+                        let action = self.message_handler.helo(&self.ctx, &msg);
+                        self.send_reply(s, action, MilterProtocol::NO_REPLY_HELO)?;
+                    }
+                    MilterMessage::MailFrom { sender, args } => {
+                        self.ctx.set_sender(EnvelopeAddress::new(&sender, &args));
+                        let action = self.message_handler.mail_from(&self.ctx, &sender, &args);
+                        self.send_reply(s, action, MilterProtocol::NO_REPLY_MAIL)?;
+                    }
+                    MilterMessage::OptionNegotiation {
+                        version,
+                        actions,
+                        protocol,
+                    } => match self.config.negotiate(version, actions, protocol) {
+                        Ok(options) => {
+                            Milter::send_response(
+                                s,
+                                ResponseMessage::option_negotiation(&options)?,
+                            )?;
+                            self.ctx.set_options(options);
+                        }
+                        Err(e) => {
+                            eprintln!("Option negotiation failed: {}", e);
+                            keep_open = false;
+                        }
+                    },
+                    MilterMessage::QuitCommunication => keep_open = false,
+                    MilterMessage::QuitNewConnection => {
+                        // The MTA reuses this connection for a new SMTP session. The negotiated
+                        // options stay valid, so only the per-connection state is reset.
+                        self.message_handler.close(&self.ctx);
+                        self.ctx.reset_connection();
+                    }
+                    MilterMessage::RecipientInformation { recipient, args } => {
+                        self.ctx
+                            .add_recipient(EnvelopeAddress::new(&recipient, &args));
+                        let action = self.message_handler.recipient(&self.ctx, &recipient, &args);
+                        self.send_reply(s, action, MilterProtocol::NO_REPLY_RECIPIENT)?;
+                    }
+                    MilterMessage::UnknownCommand { command } => {
+                        let action = self.message_handler.unknown_command(&self.ctx, &command);
+                        self.send_reply(s, action, MilterProtocol::NO_REPLY_UNKNOWN)?;
+                    }
+                };
+            }
+            Err(_e) => Milter::send_response(s, AcceptRejectAction::Continue)?,
+        }
+
+        Ok(keep_open)
+    }
+
+    pub(crate) fn handle_stream<S: SessionStream>(&mut self, stream: S) -> Result<(), MilterError> {
+        let result = self.receive(stream);
+
+        // Every way the connection ends (including timeouts and errors) leads here
+        self.message_handler.close(&self.ctx);
+
+        result
+    }
+
+    pub(crate) fn new(
+        message_handler: &'a mut dyn MessageHandler,
+        config: &'a MilterConfig,
+    ) -> Self {
+        Self {
+            config,
+            ctx: SessionContext::new(),
+            message_handler,
+        }
+    }
+
+    /// Receives and handles the messages of the MTA until the connection is closed.
+    fn receive<S: SessionStream>(&mut self, mut stream: S) -> Result<(), MilterError> {
+        let mut buffer = [0; 128];
+        let mut collected_bytes = Vec::new();
+        let mut read_timeout_kind = None;
+
+        stream.set_write_timeout(self.config.write_timeout)?;
+
+        loop {
+            // Waiting for a new message is covered by the idle timeout, waiting for the rest of a
+            // started message by the read timeout
+            let timeout_kind = if collected_bytes.is_empty() {
+                TimeoutKind::Idle
+            } else {
+                TimeoutKind::Read
+            };
+
+            if read_timeout_kind != Some(timeout_kind) {
+                stream.set_read_timeout(self.config.timeout(timeout_kind))?;
+                read_timeout_kind = Some(timeout_kind);
+            }
+
+            match stream.read(&mut buffer) {
+                Ok(0) => {
+                    println!("Closing connection");
+                    break;
+                }
+                Ok(len) => {
+                    // First, add everything read to collected_bytes
+                    collected_bytes.extend_from_slice(&buffer[..len]);
+
+                    while let Some(msg) = next_message(&mut collected_bytes)? {
+                        match self.handle_message(&mut stream, &msg) {
+                            Ok(true) => {}
+                            Ok(false) => return Ok(()),
+                            Err(MilterError::IoError(e)) if is_timeout(&e) => {
+                                self.timed_out(TimeoutKind::Write);
+                                return Ok(());
+                            }
+                            Err(e) => return Err(e),
+                        }
+                    }
+                }
+                Err(e) if is_timeout(&e) => {
+                    self.timed_out(timeout_kind);
+                    break;
+                }
+                Err(e) => {
+                    eprintln!("Error while receiving data: {}", e);
+                    break;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Sends the reply of a `MessageHandler` method (see `reply_action`).
+    fn send_reply(
+        &self,
+        s: &mut (dyn Write + Send),
+        action: AcceptRejectAction,
+        no_reply: MilterProtocol,
+    ) -> Result<(), MilterError> {
+        match reply_action(self.ctx.options(), action, no_reply) {
+            Some(action) => Milter::send_response(s, action),
+            None => Ok(()),
+        }
+    }
+
+    fn timed_out(&mut self, kind: TimeoutKind) {
+        eprintln!("Closing connection after {:?} timeout", kind);
+        self.message_handler.timeout(&self.ctx, kind);
+    }
+}
+
+/// Returns `true` if `e` has been caused by an exceeded read or write timeout.
+pub(crate) fn is_timeout(e: &std::io::Error) -> bool {
+    matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut)
+}
+
+/// Removes the next complete message from `collected_bytes`, if available.
+///
+/// The length prefix is only removed when the complete message is available.
+pub(crate) fn next_message(collected_bytes: &mut Vec<u8>) -> Result<Option<Vec<u8>>, MilterError> {
+    let u32_size = std::mem::size_of::<u32>();
+
+    if collected_bytes.len() < u32_size {
+        return Ok(None);
+    }
+
+    let msg_len: usize = u32::from_be_bytes(collected_bytes[..u32_size].try_into()?).try_into()?;
+
+    if collected_bytes.len() < u32_size + msg_len {
+        return Ok(None);
+    }
+
+    collected_bytes.drain(..u32_size);
+    Ok(Some(collected_bytes.drain(..msg_len).collect()))
+}
+
+/// Returns the action to reply with for a message part, or `None` if the MTA agreed on not
+/// expecting a reply for this message part (`no_reply`).
+///
+/// `AcceptRejectAction::Skip` is replaced by `AcceptRejectAction::Continue` unless it is the
+/// reply to a body chunk and skipping has been negotiated, which is logged once.
+pub(crate) fn reply_action(
+    options: &NegotiatedOptions,
+    action: AcceptRejectAction,
+    no_reply: MilterProtocol,
+) -> Option<AcceptRejectAction> {
+    static SKIP_REPLACED: AtomicBool = AtomicBool::new(false);
+
+    let action = match action {
+        AcceptRejectAction::Skip
+            if no_reply != MilterProtocol::NO_REPLY_BODY
+                || !options.protocol.contains(MilterProtocol::SKIP) =>
+        {
+            if !SKIP_REPLACED.swap(true, Ordering::Relaxed) {
+                eprintln!(
+                    "Replacing Skip outside of body chunks or without MTA support with Continue"
+                );
+            }
+
+            AcceptRejectAction::Continue
+        }
+        action => action,
+    };
+
+    if !options.protocol.contains(no_reply) {
+        Some(action)
+    } else {
+        if !matches!(action, AcceptRejectAction::Continue) {
+            eprintln!(
+                "Ignoring action for message part without reply: {:?}",
+                no_reply
+            );
+        }
+
+        None
+    }
+}
+
+/// A stream accepted from a listener that can be handled by a `Session`.
+pub(crate) trait SessionStream: Read + Write + Send + 'static {
+    /// Shuts down both directions of the stream, which also affects all of its clones.
+    fn close(&self);
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> std::io::Result<()>;
+
+    fn set_write_timeout(&self, timeout: Option<Duration>) -> std::io::Result<()>;
+
+    fn try_clone_stream(&self) -> std::io::Result<Self>
+    where
+        Self: Sized;
+}
+
+impl SessionStream for TcpStream {
+    fn close(&self) {
+        let _ = self.shutdown(Shutdown::Both);
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> std::io::Result<()> {
+        TcpStream::set_read_timeout(self, timeout)
+    }
+
+    fn set_write_timeout(&self, timeout: Option<Duration>) -> std::io::Result<()> {
+        TcpStream::set_write_timeout(self, timeout)
+    }
+
+    fn try_clone_stream(&self) -> std::io::Result<Self> {
+        self.try_clone()
+    }
+}
+
+#[cfg(unix)]
+impl SessionStream for UnixStream {
+    fn close(&self) {
+        let _ = self.shutdown(Shutdown::Both);
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> std::io::Result<()> {
+        UnixStream::set_read_timeout(self, timeout)
+    }
+
+    fn set_write_timeout(&self, timeout: Option<Duration>) -> std::io::Result<()> {
+        UnixStream::set_write_timeout(self, timeout)
+    }
+
+    fn try_clone_stream(&self) -> std::io::Result<Self> {
+        self.try_clone()
+    }
+}
+
+/// Keeps track of the sessions handled by worker threads.
+#[derive(Clone, Default)]
+pub(crate) struct ActiveSessions(Arc<(Mutex<usize>, Condvar)>);
+
+impl ActiveSessions {
+    /// Registers a new session, waiting until less than `max` sessions are active.
+    ///
+    /// The session is active until the returned guard is dropped. Returns `None` if a shutdown
+    /// is requested while waiting.
+    pub(crate) fn acquire(
+        &self,
+        max: Option<usize>,
+        shutdown: &ShutdownHandle,
+    ) -> Option<ActiveSessionGuard> {
+        let (count, condvar) = &*self.0;
+        let mut count = count.lock().unwrap_or_else(|e| e.into_inner());
+
+        while max.is_some_and(|max| *count >= max) {
+            if shutdown.is_shutdown() {
+                return None;
+            }
+
+            count = condvar.wait(count).unwrap_or_else(|e| e.into_inner());
+        }
+
+        *count += 1;
+
+        Some(ActiveSessionGuard(self.clone()))
+    }
+
+    /// Wakes up all threads waiting in `acquire`, so they notice a shutdown request.
+    pub(crate) fn wake(&self) {
+        let (count, condvar) = &*self.0;
+        let _count = count.lock().unwrap_or_else(|e| e.into_inner());
+
+        condvar.notify_all();
+    }
+
+    /// Waits until no session is active anymore, but not longer than `deadline`.
+    ///
+    /// Returns `false` if there are still active sessions at `deadline`.
+    pub(crate) fn wait_idle(&self, deadline: Instant) -> bool {
+        let (count, condvar) = &*self.0;
+        let mut count = count.lock().unwrap_or_else(|e| e.into_inner());
+
+        while *count > 0 {
+            let timeout = match deadline.checked_duration_since(Instant::now()) {
+                Some(timeout) if !timeout.is_zero() => timeout,
+                _ => return false,
+            };
+
+            count = condvar
+                .wait_timeout(count, timeout)
+                .unwrap_or_else(|e| e.into_inner())
+                .0;
+        }
+
+        true
+    }
+}
+
+/// Marks a session as active as long as it exists.
+pub(crate) struct ActiveSessionGuard(ActiveSessions);
+
+impl Drop for ActiveSessionGuard {
+    fn drop(&mut self) {
+        let (count, condvar) = &*(self.0).0;
+        let mut count = count.lock().unwrap_or_else(|e| e.into_inner());
+
+        *count -= 1;
+        condvar.notify_all();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::milter_message::MilterActions;
+    use std::net::TcpListener;
+    use std::sync::mpsc;
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn next_message_waits_for_complete_message() {
+        let mut collected_bytes = vec![0, 0, 0, 2, b'H'];
+
+        assert!(next_message(&mut collected_bytes).unwrap().is_none());
+
+        collected_bytes.extend_from_slice(&[0, 0, 0, 0, 1]);
+
+        assert_eq!(
+            Some(vec![b'H', 0]),
+            next_message(&mut collected_bytes).unwrap()
+        );
+        assert_eq!(&[0, 0, 0, 1][..], &collected_bytes[..]);
+        assert!(next_message(&mut collected_bytes).unwrap().is_none());
+    }
+
+    #[test]
+    fn reply_action_without_reply() {
+        let options = NegotiatedOptions {
+            protocol: MilterProtocol::NO_REPLY_HEADER | MilterProtocol::SKIP,
+            ..NegotiatedOptions::default()
+        };
+
+        assert!(reply_action(
+            &options,
+            AcceptRejectAction::Continue,
+            MilterProtocol::NO_REPLY_HEADER
+        )
+        .is_none());
+        assert!(matches!(
+            reply_action(
+                &options,
+                AcceptRejectAction::Skip,
+                MilterProtocol::NO_REPLY_BODY
+            ),
+            Some(AcceptRejectAction::Skip)
+        ));
+        assert!(matches!(
+            reply_action(
+                &options,
+                AcceptRejectAction::Skip,
+                MilterProtocol::NO_REPLY_HELO
+            ),
+            Some(AcceptRejectAction::Continue)
+        ));
+    }
+
+    #[test]
+    fn reply_action_replaces_skip_without_negotiated_skip() {
+        let options = NegotiatedOptions::default();
+
+        assert!(matches!(
+            reply_action(
+                &options,
+                AcceptRejectAction::Skip,
+                MilterProtocol::NO_REPLY_BODY
+            ),
+            Some(AcceptRejectAction::Continue)
+        ));
+    }
+
+    #[test]
+    fn handle_message_without_header_reply() {
+        let config = MilterConfig {
+            protocol: Some(MilterProtocol::NO_REPLY_HEADER),
+            ..MilterConfig::default()
+        };
+        let mut handler = TimeoutHandler {
+            closed: false,
+            timeout: None,
+        };
+        let mut session = Session::new(&mut handler, &config);
+        let mut replies = Vec::new();
+
+        // Option negotiation (version 6, no actions, SMFIP_NR_HDR offered)
+        let negotiation = b"O\x00\x00\x00\x06\x00\x00\x00\x00\x00\x00\x00\x80";
+        assert!(session.handle_message(&mut replies, negotiation).unwrap());
+        assert_eq!(&[0, 0, 0, 13, b'O'], &replies[..5]);
+        assert_eq!(&[0, 0, 0, 0x80], &replies[13..17]);
+        replies.clear();
+
+        assert!(session
+            .handle_message(&mut replies, b"Hclient.example.org\x00")
+            .unwrap());
+        assert_eq!(&[0, 0, 0, 1, b'c'], &replies[..]);
+        replies.clear();
+
+        assert!(session
+            .handle_message(&mut replies, b"LSubject\x00Hello\x00")
+            .unwrap());
+        assert!(replies.is_empty());
+
+        assert!(session.handle_message(&mut replies, b"N").unwrap());
+        assert_eq!(&[0, 0, 0, 1, b'c'], &replies[..]);
+    }
+
+    struct TimeoutHandler {
+        closed: bool,
+        timeout: Option<TimeoutKind>,
+    }
+
+    impl MessageHandler for TimeoutHandler {
+        fn close(&mut self, _ctx: &SessionContext) {
+            assert!(!self.closed);
+            self.closed = true;
+        }
+
+        fn timeout(&mut self, _ctx: &SessionContext, kind: TimeoutKind) {
+            self.timeout = Some(kind);
+        }
+    }
+
+    struct ContextHandler {
+        aborted_message_id: Option<u64>,
+        checks: usize,
+    }
+
+    impl MessageHandler for ContextHandler {
+        fn abort_filter_checks(&mut self, ctx: &SessionContext) {
+            assert_eq!(2, ctx.recipients().len());
+            self.aborted_message_id = Some(ctx.message_id());
+        }
+
+        fn data(&mut self, ctx: &SessionContext) -> AcceptRejectAction {
+            assert_ne!(self.aborted_message_id, Some(ctx.message_id()));
+            assert_eq!(Some("client.example.org"), ctx.helo());
+            assert!(ctx.sender().is_none());
+            assert!(ctx.recipients().is_empty());
+            assert!(ctx.headers().is_empty());
+            assert_eq!(None, ctx.macros().queue_id());
+            self.checks += 1;
+            AcceptRejectAction::Continue
+        }
+
+        fn end_of_header(&mut self, ctx: &SessionContext) -> AcceptRejectAction {
+            let connection = ctx.connection().unwrap();
+            assert_eq!("192.0.2.1", connection.address());
+            assert_eq!(25, connection.port());
+
+            let sender = ctx.sender().unwrap();
+            assert_eq!("<alice@example.org>", sender.address());
+            assert_eq!("SIZE=1024", sender.args()[0]);
+
+            assert_eq!("<carol@example.org>", ctx.recipients()[1].address());
+            assert_eq!("Hello", ctx.header("subject").unwrap().value());
+            assert_eq!(Some("4FDB12C0A3"), ctx.macros().queue_id());
+            self.checks += 1;
+            AcceptRejectAction::Continue
+        }
+    }
+
+    #[test]
+    fn handle_message_maintains_context() {
+        let config = MilterConfig::default();
+        let mut handler = ContextHandler {
+            aborted_message_id: None,
+            checks: 0,
+        };
+        let mut session = Session::new(&mut handler, &config);
+        let mut replies = Vec::new();
+
+        let messages: [&[u8]; 10] = [
+            b"Cclient.example.org\x004\x00\x19192.0.2.1\x00",
+            b"Hclient.example.org\x00",
+            b"DMi\x004FDB12C0A3\x00",
+            b"M<alice@example.org>\x00SIZE=1024\x00",
+            b"R<bob@example.org>\x00",
+            b"R<carol@example.org>\x00",
+            b"LSubject\x00Hello\x00",
+            b"N",
+            b"A",
+            b"T",
+        ];
+
+        for msg in &messages {
+            assert!(session.handle_message(&mut replies, msg).unwrap());
+        }
+
+        assert_eq!(2, handler.checks);
+        assert!(handler.aborted_message_id.is_some());
+    }
+
+    struct CloseHandler {
+        closed: Vec<Option<String>>,
+        helos: Vec<(String, Option<String>)>,
+    }
+
+    impl MessageHandler for CloseHandler {
+        fn close(&mut self, ctx: &SessionContext) {
+            self.closed.push(ctx.helo().map(String::from));
+        }
+
+        fn helo(&mut self, ctx: &SessionContext, _msg: &str) -> AcceptRejectAction {
+            self.helos.push((
+                ctx.connection().unwrap().address().into(),
+                ctx.macros().tls_version().map(String::from),
+            ));
+            AcceptRejectAction::Continue
+        }
+    }
+
+    #[test]
+    fn handle_stream_resets_connection_and_closes_on_eof() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut mta = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (stream, _) = listener.accept().unwrap();
+
+        let messages: [&[u8]; 6] = [
+            b"Ca.example.org\x004\x00\x19192.0.2.1\x00",
+            b"DH{tls_version}\x00TLSv1.3\x00",
+            b"Ha.example.org\x00",
+            b"K",
+            b"Cb.example.org\x004\x00\x19192.0.2.2\x00",
+            b"Hb.example.org\x00",
+        ];
+        for msg in &messages {
+            mta.write_all(&(msg.len() as u32).to_be_bytes()).unwrap();
+            mta.write_all(msg).unwrap();
+        }
+        mta.shutdown(Shutdown::Write).unwrap();
+
+        let config = MilterConfig::default();
+        let mut handler = CloseHandler {
+            closed: Vec::new(),
+            helos: Vec::new(),
+        };
+        Session::new(&mut handler, &config)
+            .handle_stream(stream)
+            .unwrap();
+
+        assert_eq!(
+            vec![
+                ("192.0.2.1".to_string(), Some("TLSv1.3".to_string())),
+                ("192.0.2.2".to_string(), None),
+            ],
+            handler.helos
+        );
+        assert_eq!(
+            vec![
+                Some("a.example.org".to_string()),
+                Some("b.example.org".to_string()),
+            ],
+            handler.closed
+        );
+    }
+
+    #[test]
+    fn handle_stream_closes_after_failed_negotiation() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut mta = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (stream, _) = listener.accept().unwrap();
+
+        // The MTA doesn't offer the requested SMFIF_ADDHDRS
+        mta.write_all(&[0, 0, 0, 13, b'O', 0, 0, 0, 6, 0, 0, 0, 0, 0, 0, 0, 0])
+            .unwrap();
+
+        let config = MilterConfig {
+            actions: Some(MilterActions::ADD_HEADERS),
+            ..MilterConfig::default()
+        };
+        let mut handler = CloseHandler {
+            closed: Vec::new(),
+            helos: Vec::new(),
+        };
+        Session::new(&mut handler, &config)
+            .handle_stream(stream)
+            .unwrap();
+
+        assert_eq!(vec![None], handler.closed);
+    }
+
+    #[test]
+    fn handle_stream_closes_incomplete_message_after_read_timeout() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut mta = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (stream, _) = listener.accept().unwrap();
+
+        let config = MilterConfig {
+            read_timeout: Some(Duration::from_millis(50)),
+            ..MilterConfig::default()
+        };
+        let mut handler = TimeoutHandler {
+            closed: false,
+            timeout: None,
+        };
+
+        mta.write_all(&[0, 0, 0, 2, b'H']).unwrap();
+        Session::new(&mut handler, &config)
+            .handle_stream(stream)
+            .unwrap();
+
+        assert_eq!(Some(TimeoutKind::Read), handler.timeout);
+        assert!(handler.closed);
+        assert_eq!(0, mta.read(&mut [0; 8]).unwrap());
+    }
+
+    #[test]
+    fn active_sessions_waits_for_free_slot() {
+        let active_sessions = ActiveSessions::default();
+        let shutdown = ShutdownHandle::new(Duration::from_secs(1), active_sessions.clone());
+        let guard = active_sessions.acquire(Some(1), &shutdown);
+        let (tx, rx) = mpsc::channel();
+
+        let waiting = active_sessions.clone();
+        thread::spawn(move || {
+            let guard = waiting.acquire(Some(1), &shutdown);
+            tx.send(guard.is_some()).unwrap();
+        });
+
+        assert!(rx.recv_timeout(Duration::from_millis(100)).is_err());
+        drop(guard);
+        assert!(rx.recv_timeout(Duration::from_secs(5)).unwrap());
+    }
+
+    #[test]
+    fn active_sessions_stops_waiting_on_shutdown() {
+        let active_sessions = ActiveSessions::default();
+        let shutdown = ShutdownHandle::new(Duration::from_secs(1), active_sessions.clone());
+        let _guard = active_sessions.acquire(Some(1), &shutdown);
+        let (tx, rx) = mpsc::channel();
+
+        let waiting = active_sessions.clone();
+        let waiting_shutdown = shutdown.clone();
+        thread::spawn(move || {
+            let guard = waiting.acquire(Some(1), &waiting_shutdown);
+            tx.send(guard.is_some()).unwrap();
+        });
+
+        assert!(rx.recv_timeout(Duration::from_millis(100)).is_err());
+        shutdown.shutdown();
+        assert!(!rx.recv_timeout(Duration::from_secs(5)).unwrap());
+        shutdown.stopped();
+    }
+
+    #[test]
+    fn active_sessions_wait_idle_until_deadline() {
+        let active_sessions = ActiveSessions::default();
+        let shutdown = ShutdownHandle::new(Duration::from_secs(1), active_sessions.clone());
+
+        assert!(active_sessions.wait_idle(Instant::now()));
+
+        let guard = active_sessions.acquire(None, &shutdown);
+
+        assert!(!active_sessions.wait_idle(Instant::now() + Duration::from_millis(50)));
+
+        thread::spawn(move || {
+            thread::sleep(Duration::from_millis(50));
+            drop(guard);
+        });
+
+        assert!(active_sessions.wait_idle(Instant::now() + Duration::from_secs(5)));
+    }
+}
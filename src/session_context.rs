@@ -0,0 +1,352 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use crate::macro_store::MacroStore;
+use crate::milter_message::{
+    MilterActions, MilterHeader, MilterMacro, MilterProtocol, NegotiatedOptions, ProtocolFamily,
+};
+
+/// The id of the next message, unique within the process.
+static NEXT_MESSAGE_ID: AtomicU64 = AtomicU64::new(1);
+
+/// Information about the SMTP client connected to the MTA (SMFIC_CONNECT).
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ConnectionInfo {
+    address: String,
+    family: ProtocolFamily,
+    hostname: String,
+    port: u16,
+}
+
+impl ConnectionInfo {
+    /// Returns the IP address or socket path of the client.
+    pub fn address(&self) -> &str {
+        &self.address
+    }
+
+    /// Returns the protocol family used by the client.
+    pub fn family(&self) -> ProtocolFamily {
+        self.family
+    }
+
+    /// Returns the hostname of the client.
+    pub fn hostname(&self) -> &str {
+        &self.hostname
+    }
+
+    pub(crate) fn new(hostname: &str, family: ProtocolFamily, port: u16, address: &str) -> Self {
+        Self {
+            address: address.into(),
+            family,
+            hostname: hostname.into(),
+            port,
+        }
+    }
+
+    /// Returns the port of the client (Inet4 and Inet6 only).
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+}
+
+/// An envelope sender or recipient including its ESMTP arguments.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct EnvelopeAddress {
+    address: String,
+    args: Vec<String>,
+}
+
+impl EnvelopeAddress {
+    /// Returns the address (e.g. `<alice@example.org>`).
+    pub fn address(&self) -> &str {
+        &self.address
+    }
+
+    /// Returns the ESMTP arguments (e.g. `SIZE=1024`).
+    pub fn args(&self) -> &[String] {
+        &self.args
+    }
+
+    pub(crate) fn new(address: &str, args: &[String]) -> Self {
+        Self {
+            address: address.into(),
+            args: args.to_vec(),
+        }
+    }
+}
+
+/// The state of a connection and its current message, maintained by rmilter and passed to each
+/// `MessageHandler` method.
+///
+/// The context is updated before the `MessageHandler` method is called, e.g. `recipients`
+/// already contains the recipient passed to `MessageHandler::recipient`.
+///
+/// The message part (sender, recipients, headers, the macros of the message and the message id)
+/// is reset after `MessageHandler::end_of_body` and `MessageHandler::abort_filter_checks` have
+/// been called. The connection part is kept until the connection is closed or reused for a new
+/// SMTP session.
+///
+/// # Example
+/// ```
+/// use rmilter::accept_reject_action::AcceptRejectAction;
+/// use rmilter::message_handler::MessageHandler;
+/// use rmilter::message_modifier::MessageModifier;
+/// use rmilter::session_context::SessionContext;
+///
+/// struct MyMessageHandler {}
+///
+/// impl MessageHandler for MyMessageHandler {
+///     fn end_of_body(
+///         &mut self,
+///         ctx: &SessionContext,
+///         modifier: &mut MessageModifier,
+///     ) -> AcceptRejectAction {
+///         println!(
+///             "message: {}, helo: {:?}, recipients: {}, headers: {}",
+///             ctx.message_id(),
+///             ctx.helo(),
+///             ctx.recipients().len(),
+///             ctx.headers().len()
+///         );
+///         AcceptRejectAction::Continue
+///     }
+/// }
+/// ```
+#[derive(Debug)]
+pub struct SessionContext {
+    connection: Option<ConnectionInfo>,
+    headers: Vec<MilterHeader>,
+    helo: Option<String>,
+    macros: MacroStore,
+    message_id: u64,
+    options: NegotiatedOptions,
+    recipients: Vec<EnvelopeAddress>,
+    sender: Option<EnvelopeAddress>,
+}
+
+impl SessionContext {
+    /// Returns the actions negotiated with the MTA.
+    pub fn actions(&self) -> MilterActions {
+        self.options.actions
+    }
+
+    pub(crate) fn add_header(&mut self, header: MilterHeader) {
+        self.headers.push(header);
+    }
+
+    pub(crate) fn add_recipient(&mut self, recipient: EnvelopeAddress) {
+        self.recipients.push(recipient);
+    }
+
+    /// Returns the information about the SMTP client, if sent by the MTA.
+    pub fn connection(&self) -> Option<&ConnectionInfo> {
+        self.connection.as_ref()
+    }
+
+    pub(crate) fn define_macros(&mut self, cmdcode: char, macros: Vec<MilterMacro>) {
+        self.macros.define(cmdcode, macros);
+    }
+
+    /// Returns the first header of the current message named `name` (case-insensitive).
+    ///
+    /// # Example:
+    /// ```
+    /// use rmilter::accept_reject_action::AcceptRejectAction;
+    /// use rmilter::message_handler::MessageHandler;
+    /// use rmilter::session_context::SessionContext;
+    ///
+    /// struct MyMessageHandler {}
+    ///
+    /// impl MessageHandler for MyMessageHandler {
+    ///     fn end_of_header(&mut self, ctx: &SessionContext) -> AcceptRejectAction {
+    ///         match ctx.header("message-id") {
+    ///             Some(_) => AcceptRejectAction::Continue,
+    ///             None => AcceptRejectAction::Reject,
+    ///         }
+    ///     }
+    /// }
+    /// ```
+    pub fn header(&self, name: &str) -> Option<&MilterHeader> {
+        self.headers
+            .iter()
+            .find(|header| header.name().eq_ignore_ascii_case(name))
+    }
+
+    /// Returns the headers of the current message received so far.
+    pub fn headers(&self) -> &[MilterHeader] {
+        &self.headers
+    }
+
+    /// Returns the HELO/EHLO name sent by the SMTP client.
+    pub fn helo(&self) -> Option<&str> {
+        self.helo.as_deref()
+    }
+
+    /// Returns the macros defined by the MTA for the connection and the current message.
+    pub fn macros(&self) -> &MacroStore {
+        &self.macros
+    }
+
+    /// Returns the id of the current message.
+    ///
+    /// The id is unique within the process and changes after each message, so it can be used to
+    /// relate log lines or state to a message. It is not related to the queue id of the MTA (see
+    /// `MacroStore::queue_id`).
+    pub fn message_id(&self) -> u64 {
+        self.message_id
+    }
+
+    /// Creates an empty SessionContext without connection information or macros.
+    ///
+    /// # Example:
+    /// ```
+    /// use rmilter::session_context::SessionContext;
+    ///
+    /// let ctx = SessionContext::new();
+    ///
+    /// assert!(ctx.sender().is_none());
+    /// assert!(ctx.recipients().is_empty());
+    /// ```
+    pub fn new() -> Self {
+        Self {
+            connection: None,
+            headers: Vec::new(),
+            helo: None,
+            macros: MacroStore::new(),
+            message_id: next_message_id(),
+            options: NegotiatedOptions::default(),
+            recipients: Vec::new(),
+            sender: None,
+        }
+    }
+
+    pub(crate) fn options(&self) -> &NegotiatedOptions {
+        &self.options
+    }
+
+    /// Returns the protocol flags negotiated with the MTA.
+    pub fn protocol(&self) -> MilterProtocol {
+        self.options.protocol
+    }
+
+    /// Returns the envelope recipients of the current message received so far.
+    pub fn recipients(&self) -> &[EnvelopeAddress] {
+        &self.recipients
+    }
+
+    /// Resets the state of the connection when it is reused for a new SMTP session. The
+    /// negotiated options stay valid.
+    pub(crate) fn reset_connection(&mut self) {
+        self.connection = None;
+        self.helo = None;
+        self.macros = MacroStore::new();
+        self.reset_message();
+    }
+
+    /// Resets the state of the current message and assigns a new message id.
+    pub(crate) fn reset_message(&mut self) {
+        self.headers.clear();
+        self.macros.clear_message();
+        self.message_id = next_message_id();
+        self.recipients.clear();
+        self.sender = None;
+    }
+
+    /// Returns the envelope sender of the current message.
+    pub fn sender(&self) -> Option<&EnvelopeAddress> {
+        self.sender.as_ref()
+    }
+
+    pub(crate) fn set_connection(&mut self, connection: ConnectionInfo) {
+        self.connection = Some(connection);
+    }
+
+    pub(crate) fn set_helo(&mut self, helo: &str) {
+        self.helo = Some(helo.into());
+    }
+
+    pub(crate) fn set_options(&mut self, options: NegotiatedOptions) {
+        self.options = options;
+    }
+
+    pub(crate) fn set_sender(&mut self, sender: EnvelopeAddress) {
+        self.sender = Some(sender);
+    }
+
+    /// Returns the milter protocol version negotiated with the MTA.
+    pub fn version(&self) -> u32 {
+        self.options.version
+    }
+}
+
+impl Default for SessionContext {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn next_message_id() -> u64 {
+    NEXT_MESSAGE_ID.fetch_add(1, Ordering::Relaxed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reset_message_keeps_connection() {
+        let mut ctx = SessionContext::new();
+        ctx.set_connection(ConnectionInfo::new(
+            "client.example.org",
+            ProtocolFamily::Inet4,
+            25,
+            "192.0.2.1",
+        ));
+        ctx.set_helo("client.example.org");
+        ctx.define_macros('C', vec![MilterMacro::new("j", "mx.example.org")]);
+        ctx.define_macros('M', vec![MilterMacro::new("i", "4FDB12C0A3")]);
+        ctx.set_sender(EnvelopeAddress::new("<alice@example.org>", &[]));
+        ctx.add_recipient(EnvelopeAddress::new("<bob@example.org>", &[]));
+        ctx.add_header(MilterHeader::new("Subject", b"Hello"));
+
+        let message_id = ctx.message_id();
+        ctx.reset_message();
+
+        assert_ne!(message_id, ctx.message_id());
+        assert_eq!("192.0.2.1", ctx.connection().unwrap().address());
+        assert_eq!(Some("client.example.org"), ctx.helo());
+        assert_eq!(Some("mx.example.org"), ctx.macros().get("j"));
+        assert_eq!(None, ctx.macros().queue_id());
+        assert!(ctx.sender().is_none());
+        assert!(ctx.recipients().is_empty());
+        assert!(ctx.headers().is_empty());
+    }
+
+    #[test]
+    fn reset_connection_keeps_options() {
+        let mut ctx = SessionContext::new();
+        ctx.set_options(NegotiatedOptions {
+            version: 6,
+            ..NegotiatedOptions::default()
+        });
+        ctx.set_helo("client.example.org");
+        ctx.define_macros('C', vec![MilterMacro::new("j", "mx.example.org")]);
+
+        ctx.reset_connection();
+
+        assert_eq!(6, ctx.version());
+        assert_eq!(None, ctx.helo());
+        assert_eq!(None, ctx.macros().get("j"));
+    }
+
+    #[test]
+    fn header_is_case_insensitive() {
+        let mut ctx = SessionContext::new();
+        ctx.add_header(MilterHeader::new("Message-ID", b"<1@example.org>"));
+
+        assert_eq!(
+            b"<1@example.org>",
+            ctx.header("message-id").unwrap().raw_value()
+        );
+        assert!(ctx.header("Subject").is_none());
+    }
+}
@@ -0,0 +1,331 @@
+use std::collections::HashMap;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, TcpStream};
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
+#[cfg(unix)]
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use crate::session::{ActiveSessions, SessionStream};
+
+/// Time the running sessions get to finish after a shutdown has been requested, unless defined
+/// with `MilterBuilder::set_shutdown_timeout`.
+pub(crate) const DEFAULT_SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Used to stop a running `Milter` (or `AsyncMilter`) from another thread (e.g. a signal
+/// handler) or task.
+///
+/// After `shutdown` has been called, the milter stops accepting new connections and waits for
+/// the running sessions to finish. Connections still open after the shutdown timeout (see
+/// `MilterBuilder::set_shutdown_timeout`) are closed, then `Milter::run` returns.
+///
+/// # Example
+/// ```no_run
+/// use std::thread;
+/// use std::time::Duration;
+///
+/// use rmilter::milter_builder::MilterBuilder;
+/// use rmilter::message_handler::MessageHandler;
+///
+/// struct MyHandler;
+/// impl MessageHandler for MyHandler {}
+///
+/// let mut milter = MilterBuilder::from_factory(|| MyHandler {})
+///     .set_shutdown_timeout(Duration::from_secs(10))
+///     .build();
+/// let shutdown_handle = milter.shutdown_handle();
+///
+/// thread::spawn(move || {
+///     // Wait for SIGTERM, e.g. using the signal-hook crate
+///     shutdown_handle.shutdown();
+/// });
+///
+/// milter.run("127.0.0.1:31337").expect("Failed to run milter");
+/// ```
+#[derive(Clone)]
+pub struct ShutdownHandle(Arc<ShutdownState>);
+
+struct ShutdownState {
+    listener: Mutex<Option<ListenerAddress>>,
+    next_stream_id: AtomicU64,
+    requested: AtomicBool,
+    #[cfg(feature = "tokio")]
+    requested_tx: tokio::sync::watch::Sender<bool>,
+    sessions: ActiveSessions,
+    stopped: (Mutex<bool>, Condvar),
+    streams: Mutex<HashMap<u64, Box<dyn SessionStream>>>,
+    timeout: Duration,
+}
+
+/// The address of the socket a running milter accepts connections on.
+#[derive(Clone, Debug)]
+pub(crate) enum ListenerAddress {
+    Tcp(SocketAddr),
+    #[cfg(unix)]
+    Unix(PathBuf),
+}
+
+impl ListenerAddress {
+    /// Returns the address of a TCP listener bound to `addr`, using the loopback address for
+    /// listeners bound to all interfaces.
+    pub(crate) fn tcp(mut addr: SocketAddr) -> Self {
+        if addr.ip().is_unspecified() {
+            match addr {
+                SocketAddr::V4(_) => addr.set_ip(Ipv4Addr::LOCALHOST.into()),
+                SocketAddr::V6(_) => addr.set_ip(Ipv6Addr::LOCALHOST.into()),
+            }
+        }
+
+        Self::Tcp(addr)
+    }
+
+    /// Opens and closes a connection to the listener.
+    fn connect(&self) -> std::io::Result<()> {
+        match self {
+            Self::Tcp(addr) => TcpStream::connect(addr).map(drop),
+            #[cfg(unix)]
+            Self::Unix(path) => UnixStream::connect(path).map(drop),
+        }
+    }
+}
+
+impl ShutdownHandle {
+    /// Closes all registered streams, which ends their sessions.
+    pub(crate) fn close_streams(&self) {
+        let streams = self.0.streams.lock().unwrap_or_else(|e| e.into_inner());
+
+        for stream in streams.values() {
+            stream.close();
+        }
+    }
+
+    /// Returns `true` if a shutdown has been requested.
+    ///
+    /// # Example
+    /// ```
+    /// use rmilter::milter_builder::MilterBuilder;
+    /// use rmilter::message_handler::MessageHandler;
+    ///
+    /// struct MyHandler;
+    /// impl MessageHandler for MyHandler {}
+    ///
+    /// let milter = MilterBuilder::from_factory(|| MyHandler {}).build();
+    /// let shutdown_handle = milter.shutdown_handle();
+    ///
+    /// assert!(!shutdown_handle.is_shutdown());
+    /// shutdown_handle.shutdown();
+    /// assert!(shutdown_handle.is_shutdown());
+    /// ```
+    pub fn is_shutdown(&self) -> bool {
+        self.0.requested.load(Ordering::SeqCst)
+    }
+
+    pub(crate) fn new(timeout: Duration, sessions: ActiveSessions) -> Self {
+        Self(Arc::new(ShutdownState {
+            listener: Mutex::new(None),
+            next_stream_id: AtomicU64::new(0),
+            requested: AtomicBool::new(false),
+            #[cfg(feature = "tokio")]
+            requested_tx: tokio::sync::watch::channel(false).0,
+            sessions,
+            stopped: (Mutex::new(false), Condvar::new()),
+            streams: Mutex::new(HashMap::new()),
+            timeout,
+        }))
+    }
+
+    /// Registers the stream of a running session, so it can be closed once the shutdown timeout
+    /// is exceeded.
+    ///
+    /// The stream is registered as long as the returned guard exists.
+    pub(crate) fn register(&self, stream: Box<dyn SessionStream>) -> StreamRegistration {
+        let id = self.0.next_stream_id.fetch_add(1, Ordering::SeqCst);
+
+        self.0
+            .streams
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .insert(id, stream);
+
+        StreamRegistration {
+            handle: self.clone(),
+            id,
+        }
+    }
+
+    /// Sets the address of the socket the milter accepts connections on, which is connected to
+    /// when a shutdown is requested to wake up the blocking accept.
+    ///
+    /// Without an address (e.g. for abstract unix domain sockets), the shutdown is noticed once
+    /// the next connection has been accepted.
+    pub(crate) fn set_listener(&self, listener: Option<ListenerAddress>) {
+        *self.0.listener.lock().unwrap_or_else(|e| e.into_inner()) = listener;
+    }
+
+    /// Requests the milter to shut down.
+    ///
+    /// The milter stops accepting new connections right away. Calling this method more than once
+    /// has no further effect.
+    ///
+    /// # Example
+    /// ```
+    /// use rmilter::milter_builder::MilterBuilder;
+    /// use rmilter::message_handler::MessageHandler;
+    ///
+    /// struct MyHandler;
+    /// impl MessageHandler for MyHandler {}
+    ///
+    /// let mut handler = MyHandler {};
+    ///
+    /// let mut milter = MilterBuilder::new(&mut handler).build();
+    /// milter.shutdown_handle().shutdown();
+    ///
+    /// // Returns immediately, as a shutdown has already been requested
+    /// milter.run("127.0.0.1:0").expect("Failed to run milter");
+    /// ```
+    pub fn shutdown(&self) {
+        if self.0.requested.swap(true, Ordering::SeqCst) {
+            return;
+        }
+
+        // Wakes up an accept loop waiting for a running session to finish
+        self.0.sessions.wake();
+
+        #[cfg(feature = "tokio")]
+        self.0.requested_tx.send_replace(true);
+
+        let listener = self
+            .0
+            .listener
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone();
+
+        if let Some(listener) = listener {
+            if let Err(e) = listener.connect() {
+                eprintln!("Failed to wake up the listener: {}", e);
+            }
+        }
+
+        // The milter itself can't enforce the timeout while it is handling a connection
+        // without a worker thread, so the streams are closed from a separate thread unless the
+        // milter stops before.
+        let handle = self.clone();
+        let result = thread::Builder::new()
+            .name("rmilter-shutdown".into())
+            .spawn(move || {
+                let (stopped, condvar) = &handle.0.stopped;
+                let stopped = stopped.lock().unwrap_or_else(|e| e.into_inner());
+                let (stopped, _) = condvar
+                    .wait_timeout_while(stopped, handle.0.timeout, |stopped| !*stopped)
+                    .unwrap_or_else(|e| e.into_inner());
+
+                if !*stopped {
+                    drop(stopped);
+                    handle.close_streams();
+                }
+            });
+
+        if let Err(e) = result {
+            eprintln!("Failed to start shutdown timer: {}", e);
+        }
+    }
+
+    /// Marks the milter as stopped, which ends a running shutdown timer.
+    pub(crate) fn stopped(&self) {
+        self.set_listener(None);
+
+        let (stopped, condvar) = &self.0.stopped;
+        *stopped.lock().unwrap_or_else(|e| e.into_inner()) = true;
+        condvar.notify_all();
+    }
+
+    /// Returns the time the running sessions get to finish.
+    pub(crate) fn timeout(&self) -> Duration {
+        self.0.timeout
+    }
+
+    /// Waits until a shutdown has been requested.
+    #[cfg(feature = "tokio")]
+    pub(crate) async fn wait(&self) {
+        let mut requested = self.0.requested_tx.subscribe();
+
+        while !*requested.borrow_and_update() {
+            if requested.changed().await.is_err() {
+                break;
+            }
+        }
+    }
+}
+
+/// Keeps a stream registered at the `ShutdownHandle` as long as it exists.
+pub(crate) struct StreamRegistration {
+    handle: ShutdownHandle,
+    id: u64,
+}
+
+impl Drop for StreamRegistration {
+    fn drop(&mut self) {
+        self.handle
+            .0
+            .streams
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .remove(&self.id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+    use std::net::{TcpListener, TcpStream};
+
+    #[test]
+    fn close_streams_ends_registered_sessions() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let _client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (mut stream, _) = listener.accept().unwrap();
+
+        let handle = ShutdownHandle::new(Duration::from_secs(1), ActiveSessions::default());
+        let _registration = handle.register(Box::new(stream.try_clone().unwrap()));
+
+        handle.close_streams();
+
+        assert_eq!(0, stream.read(&mut [0; 8]).unwrap());
+    }
+
+    #[test]
+    fn shutdown_timer_ends_when_stopped() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let _client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (mut stream, _) = listener.accept().unwrap();
+
+        let handle = ShutdownHandle::new(Duration::from_millis(50), ActiveSessions::default());
+        let _registration = handle.register(Box::new(stream.try_clone().unwrap()));
+
+        handle.shutdown();
+        handle.stopped();
+        thread::sleep(Duration::from_millis(200));
+
+        // The stream is still open, so reading runs into the timeout
+        stream
+            .set_read_timeout(Some(Duration::from_millis(50)))
+            .unwrap();
+        assert!(stream.read(&mut [0; 8]).is_err());
+    }
+
+    #[test]
+    fn shutdown_wakes_up_listener() {
+        let listener = TcpListener::bind("0.0.0.0:0").unwrap();
+        let handle = ShutdownHandle::new(Duration::from_secs(1), ActiveSessions::default());
+        handle.set_listener(Some(ListenerAddress::tcp(listener.local_addr().unwrap())));
+
+        handle.shutdown();
+
+        assert!(listener.accept().is_ok());
+    }
+}
@@ -0,0 +1,303 @@
+use std::env;
+use std::ffi::OsStr;
+use std::net::TcpListener;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::{FromRawFd, IntoRawFd, RawFd};
+use std::os::unix::net::{UnixDatagram, UnixListener};
+use std::path::Path;
+use std::sync::Mutex;
+
+use crate::milter_error::MilterError;
+
+/// The first file descriptor passed by systemd (SD_LISTEN_FDS_START).
+const LISTEN_FDS_START: RawFd = 3;
+
+/// The file descriptors of the sockets passed by systemd that have been taken already, as each
+/// of them must only be owned once.
+static TAKEN_FDS: Mutex<Vec<RawFd>> = Mutex::new(Vec::new());
+
+/// A listening socket passed by systemd.
+pub(crate) enum ActivatedListener {
+    Tcp(TcpListener),
+    Unix(UnixListener),
+}
+
+/// Sends a state change (e.g. `READY=1` or `STATUS=...`) to the service manager using the socket
+/// defined in `NOTIFY_SOCKET` (see `sd_notify(3)`).
+///
+/// Returns `false` if `NOTIFY_SOCKET` is not set, i.e. the milter doesn't run as a systemd
+/// service with `Type=notify`. `Milter` sends `READY=1` and `STOPPING=1` on its own.
+///
+/// # Example
+/// ```
+/// use rmilter::systemd;
+///
+/// systemd::notify("STATUS=Loading rules").expect("Failed to notify systemd");
+/// ```
+pub fn notify(state: &str) -> Result<bool, MilterError> {
+    match env::var_os("NOTIFY_SOCKET") {
+        Some(socket) => {
+            notify_socket(&socket, state)?;
+            Ok(true)
+        }
+        None => Ok(false),
+    }
+}
+
+/// Sends `state` to the notification socket `socket` (a path or an abstract socket name starting
+/// with `@`).
+fn notify_socket(socket: &OsStr, state: &str) -> Result<(), MilterError> {
+    let datagram = UnixDatagram::unbound()?;
+
+    match socket.as_bytes() {
+        [b'@', name @ ..] => send_abstract(&datagram, name, state),
+        _ => {
+            datagram.send_to(state.as_bytes(), Path::new(socket))?;
+            Ok(())
+        }
+    }
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn send_abstract(datagram: &UnixDatagram, name: &[u8], state: &str) -> Result<(), MilterError> {
+    #[cfg(target_os = "android")]
+    use std::os::android::net::SocketAddrExt;
+    #[cfg(target_os = "linux")]
+    use std::os::linux::net::SocketAddrExt;
+
+    let addr = std::os::unix::net::SocketAddr::from_abstract_name(name)?;
+    datagram.send_to_addr(state.as_bytes(), &addr)?;
+
+    Ok(())
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+fn send_abstract(_datagram: &UnixDatagram, name: &[u8], _state: &str) -> Result<(), MilterError> {
+    Err(MilterError::InvalidSocket(format!(
+        "abstract sockets are not supported on this platform: @{}",
+        String::from_utf8_lossy(name)
+    )))
+}
+
+/// Takes the listening socket passed by systemd using socket activation (see
+/// `sd_listen_fds(3)`).
+///
+/// - `name` selects the socket by its `FileDescriptorName=`. Without a name, exactly one socket
+///   must have been passed.
+///
+/// Each socket can only be taken once. The `LISTEN_*` environment variables are kept, as
+/// modifying the environment isn't safe while other threads may read it.
+pub(crate) fn take_listener(name: Option<&str>) -> Result<ActivatedListener, MilterError> {
+    let fds = listen_fds(
+        env::var("LISTEN_PID").ok().as_deref(),
+        env::var("LISTEN_FDS").ok().as_deref(),
+        env::var("LISTEN_FDNAMES").ok().as_deref(),
+        std::process::id(),
+    )?;
+
+    take_fd(&fds, name)
+}
+
+/// Takes the socket named `name` out of the passed sockets `fds` (see `take_listener`).
+fn take_fd(fds: &[(String, RawFd)], name: Option<&str>) -> Result<ActivatedListener, MilterError> {
+    let fd = match name {
+        Some(name) => fds
+            .iter()
+            .find(|(fd_name, _)| fd_name == name)
+            .map(|(_, fd)| *fd)
+            .ok_or_else(|| {
+                MilterError::InvalidSocket(format!("no socket named '{}' passed by systemd", name))
+            })?,
+        None => match fds {
+            [(_, fd)] => *fd,
+            [] => {
+                return Err(MilterError::InvalidSocket(
+                    "no socket passed by systemd".into(),
+                ))
+            }
+            _ => {
+                return Err(MilterError::InvalidSocket(
+                    "more than one socket passed by systemd, select one by name".into(),
+                ))
+            }
+        },
+    };
+
+    let mut taken_fds = TAKEN_FDS.lock().unwrap_or_else(|e| e.into_inner());
+    if taken_fds.contains(&fd) {
+        return Err(MilterError::InvalidSocket(format!(
+            "file descriptor {} passed by systemd has already been taken",
+            fd
+        )));
+    }
+
+    let listener = adopt(fd)?;
+    taken_fds.push(fd);
+
+    Ok(listener)
+}
+
+/// Parses the `LISTEN_*` environment variables into the names and file descriptors of the
+/// passed sockets.
+///
+/// The sockets are only meant for this process if `pid` matches `own_pid`.
+fn listen_fds(
+    pid: Option<&str>,
+    fds: Option<&str>,
+    names: Option<&str>,
+    own_pid: u32,
+) -> Result<Vec<(String, RawFd)>, MilterError> {
+    if pid.and_then(|pid| pid.parse::<u32>().ok()) != Some(own_pid) {
+        return Ok(Vec::new());
+    }
+
+    let count: RawFd = fds.unwrap_or("0").parse().map_err(|_| {
+        MilterError::InvalidSocket(format!("invalid LISTEN_FDS: {}", fds.unwrap_or("")))
+    })?;
+    let names: Vec<&str> = names.map(|n| n.split(':').collect()).unwrap_or_default();
+
+    Ok((0..count)
+        .map(|i| {
+            let name = if names.len() == count as usize {
+                names[i as usize]
+            } else {
+                "unknown"
+            };
+            (name.into(), LISTEN_FDS_START + i)
+        })
+        .collect())
+}
+
+/// Takes ownership of the listening socket `fd` and detects its address family.
+///
+/// Like `sd_listen_fds(3)`, the socket is marked close-on-exec, so it isn't inherited by child
+/// processes.
+fn adopt(fd: RawFd) -> Result<ActivatedListener, MilterError> {
+    set_cloexec(fd)?;
+
+    // SAFETY: systemd passes the sockets as open file descriptors owned by this process. They
+    // are only taken once, which is ensured by `TAKEN_FDS`.
+    let listener = unsafe { TcpListener::from_raw_fd(fd) };
+
+    if listener.local_addr().is_ok() {
+        return Ok(ActivatedListener::Tcp(listener));
+    }
+
+    // SAFETY: The file descriptor is released by the TcpListener first.
+    let listener = unsafe { UnixListener::from_raw_fd(listener.into_raw_fd()) };
+
+    if listener.local_addr().is_ok() {
+        Ok(ActivatedListener::Unix(listener))
+    } else {
+        Err(MilterError::InvalidSocket(format!(
+            "file descriptor {} passed by systemd is no TCP or unix domain socket",
+            fd
+        )))
+    }
+}
+
+/// Sets the close-on-exec flag of the file descriptor `fd`.
+fn set_cloexec(fd: RawFd) -> Result<(), MilterError> {
+    // SAFETY: fcntl with F_GETFD and F_SETFD only reads and changes the flags of `fd`.
+    let flags = unsafe { libc::fcntl(fd, libc::F_GETFD) };
+    if flags == -1 || unsafe { libc::fcntl(fd, libc::F_SETFD, flags | libc::FD_CLOEXEC) } == -1 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn listen_fds_with_names() {
+        let res = listen_fds(Some("42"), Some("2"), Some("milter:admin"), 42).unwrap();
+        let comp = vec![(String::from("milter"), 3), (String::from("admin"), 4)];
+
+        assert_eq!(comp, res);
+    }
+
+    #[test]
+    fn listen_fds_without_names() {
+        let res = listen_fds(Some("42"), Some("1"), None, 42).unwrap();
+
+        assert_eq!(vec![(String::from("unknown"), 3)], res);
+    }
+
+    #[test]
+    fn listen_fds_for_other_process() {
+        assert!(listen_fds(Some("41"), Some("1"), None, 42)
+            .unwrap()
+            .is_empty());
+        assert!(listen_fds(None, None, None, 42).unwrap().is_empty());
+    }
+
+    #[test]
+    fn listen_fds_invalid() {
+        assert!(listen_fds(Some("42"), Some("one"), None, 42).is_err());
+    }
+
+    #[test]
+    fn adopt_detects_address_family() {
+        let tcp = TcpListener::bind("127.0.0.1:0").unwrap();
+
+        assert!(matches!(
+            adopt(tcp.into_raw_fd()).unwrap(),
+            ActivatedListener::Tcp(_)
+        ));
+
+        let path = env::temp_dir().join(format!("rmilter-adopt-{}.sock", std::process::id()));
+        let unix = UnixListener::bind(&path).unwrap();
+
+        assert!(matches!(
+            adopt(unix.into_raw_fd()).unwrap(),
+            ActivatedListener::Unix(_)
+        ));
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn adopt_sets_cloexec() {
+        let tcp = TcpListener::bind("127.0.0.1:0").unwrap();
+        let fd = tcp.into_raw_fd();
+        unsafe { libc::fcntl(fd, libc::F_SETFD, 0) };
+
+        let _listener = adopt(fd).unwrap();
+
+        let flags = unsafe { libc::fcntl(fd, libc::F_GETFD) };
+        assert_eq!(libc::FD_CLOEXEC, flags & libc::FD_CLOEXEC);
+    }
+
+    #[test]
+    fn take_fd_takes_each_socket_once() {
+        let milter = TcpListener::bind("127.0.0.1:0").unwrap().into_raw_fd();
+        let admin = TcpListener::bind("127.0.0.1:0").unwrap().into_raw_fd();
+        let fds = vec![
+            (String::from("milter"), milter),
+            (String::from("admin"), admin),
+        ];
+
+        let _milter = take_fd(&fds, Some("milter")).unwrap();
+        let _admin = take_fd(&fds, Some("admin")).unwrap();
+
+        assert!(take_fd(&fds, Some("milter")).is_err());
+        assert!(take_fd(&fds, Some("metrics")).is_err());
+    }
+
+    #[test]
+    fn notify_socket_sends_state() {
+        let path = env::temp_dir().join(format!("rmilter-notify-{}.sock", std::process::id()));
+        let service_manager = UnixDatagram::bind(&path).unwrap();
+
+        notify_socket(path.as_os_str(), "READY=1").unwrap();
+
+        let mut buffer = [0; 16];
+        let len = service_manager.recv(&mut buffer).unwrap();
+        assert_eq!(b"READY=1", &buffer[..len]);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}